@@ -2262,6 +2262,12 @@ void changeSocks5Proxy() async {
       username = userController.text.trim();
       password = pwdController.text.trim();
 
+      proxyMsg = translate(bind.mainCheckProxy(
+          proxy: proxy, username: username, password: password));
+      if (proxyMsg.isNotEmpty) {
+        cancel();
+        return;
+      }
       if (proxy.isNotEmpty) {
         String domainPort = proxy;
         if (domainPort.contains('://')) {
@@ -686,6 +686,14 @@ class RustdeskImpl {
     return Future.value('');
   }
 
+  String mainCheckProxy(
+      {required String proxy,
+      required String username,
+      required String password,
+      dynamic hint}) {
+    return '';
+  }
+
   Future<void> mainSetSocks(
       {required String proxy,
       required String username,
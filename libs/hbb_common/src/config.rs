@@ -188,6 +188,54 @@ pub struct Config {
     keys_confirmed: HashMap<String, bool>,
 }
 
+// Where the rendezvous servers in use come from, in the order they are looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RendezvousServerSource {
+    // `EXE_RENDEZVOUS_SERVER`, from the executable name or the license
+    Executable,
+    // the `custom-rendezvous-server` option
+    Config,
+    // `RENDEZVOUS_SERVER` at build time
+    Env,
+    // `rendezvous-servers` pushed by the server with `ConfigureUpdate`
+    Pushed,
+    // `RENDEZVOUS_SERVERS`
+    Default,
+}
+
+// `pushed` is only set if the pushed list is newer than this build, see `SERIAL`.
+fn resolve_rendezvous_servers(
+    exe: String,
+    custom: String,
+    prod: String,
+    pushed: Option<String>,
+) -> (Vec<String>, RendezvousServerSource) {
+    if !exe.is_empty() {
+        return (vec![exe], RendezvousServerSource::Executable);
+    }
+    if !custom.is_empty() {
+        return (vec![custom], RendezvousServerSource::Config);
+    }
+    if !prod.is_empty() {
+        return (vec![prod], RendezvousServerSource::Env);
+    }
+    if let Some(pushed) = pushed {
+        let ss: Vec<String> = pushed
+            .split(',')
+            .filter(|x| x.contains('.'))
+            .map(|x| x.to_owned())
+            .collect();
+        if !ss.is_empty() {
+            return (ss, RendezvousServerSource::Pushed);
+        }
+    }
+    (
+        RENDEZVOUS_SERVERS.iter().map(|x| x.to_string()).collect(),
+        RendezvousServerSource::Default,
+    )
+}
+
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Socks5Server {
     #[serde(default, deserialize_with = "deserialize_string")]
@@ -725,36 +773,32 @@ impl Config {
     }
 
     pub fn get_rendezvous_servers() -> Vec<String> {
-        let s = EXE_RENDEZVOUS_SERVER.read().unwrap().clone();
-        if !s.is_empty() {
-            return vec![s];
-        }
-        let s = Self::get_option("custom-rendezvous-server");
-        if !s.is_empty() {
-            return vec![s];
-        }
-        let s = PROD_RENDEZVOUS_SERVER.read().unwrap().clone();
-        if !s.is_empty() {
-            return vec![s];
-        }
+        Self::get_rendezvous_servers_with_source().0
+    }
+
+    pub fn get_rendezvous_servers_with_source() -> (Vec<String>, RendezvousServerSource) {
         let serial_obsolute = CONFIG2.read().unwrap().serial > SERIAL;
-        if serial_obsolute {
-            let ss: Vec<String> = Self::get_option("rendezvous-servers")
-                .split(',')
-                .filter(|x| x.contains('.'))
-                .map(|x| x.to_owned())
-                .collect();
-            if !ss.is_empty() {
-                return ss;
-            }
-        }
-        return RENDEZVOUS_SERVERS.iter().map(|x| x.to_string()).collect();
+        resolve_rendezvous_servers(
+            EXE_RENDEZVOUS_SERVER.read().unwrap().clone(),
+            Self::get_option("custom-rendezvous-server"),
+            PROD_RENDEZVOUS_SERVER.read().unwrap().clone(),
+            if serial_obsolute {
+                Some(Self::get_option("rendezvous-servers"))
+            } else {
+                None
+            },
+        )
     }
 
     pub fn reset_online() {
         *ONLINE.lock().unwrap() = Default::default();
     }
 
+    // In microseconds, -1 if the host is unreachable, `None` if not measured yet.
+    pub fn get_latency(host: &str) -> Option<i64> {
+        ONLINE.lock().unwrap().get(host).cloned()
+    }
+
     pub fn update_latency(host: &str, latency: i64) {
         ONLINE.lock().unwrap().insert(host.to_owned(), latency);
         let mut host = "".to_owned();
@@ -947,6 +991,17 @@ impl Config {
         .unwrap_or_default()
     }
 
+    /// The option saved on this machine, the default and overwrite settings are ignored.
+    pub fn get_saved_option(k: &str) -> String {
+        CONFIG2
+            .read()
+            .unwrap()
+            .options
+            .get(k)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     pub fn set_option(k: String, v: String) {
         if !is_option_can_save(&OVERWRITE_SETTINGS, &k, &DEFAULT_SETTINGS, &v) {
             return;
@@ -1522,6 +1577,9 @@ pub struct DiscoveryPeer {
     pub online: bool,
     #[serde(default, deserialize_with = "deserialize_hashmap_string_string")]
     pub ip_mac: HashMap<String, String>,
+    // Only seen in an unsolicited announcement, not in an answer to a discovery of this device.
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    pub announced: bool,
 }
 
 impl DiscoveryPeer {
@@ -1566,6 +1624,61 @@ impl LanPeers {
     }
 }
 
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LanPeer {
+    #[serde(default, deserialize_with = "deserialize_string")]
+    pub id: String,
+    #[serde(default, deserialize_with = "deserialize_string")]
+    pub addr: String,
+    // in milliseconds since the unix epoch
+    #[serde(default, deserialize_with = "deserialize_i64")]
+    pub last_seen: i64,
+}
+
+/// The lan peers seen recently, most recent first, kept across restarts.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct RecentLanPeers {
+    #[serde(default, deserialize_with = "deserialize_vec_lanpeer")]
+    pub peers: Vec<LanPeer>,
+}
+
+impl RecentLanPeers {
+    pub fn load() -> RecentLanPeers {
+        let _lock = CONFIG.read().unwrap();
+        match confy::load_path(Config::file_("_recent_lan_peers")) {
+            Ok(peers) => peers,
+            Err(err) => {
+                log::error!("Failed to load recent lan peers: {}", err);
+                Default::default()
+            }
+        }
+    }
+
+    pub fn store(&self) {
+        if let Err(err) = store_path(Config::file_("_recent_lan_peers"), self.clone()) {
+            log::error!("Failed to store recent lan peers: {}", err);
+        }
+    }
+
+    /// Records `id` seen at `addr` at `now`, and drops the peers not seen within `ttl`.
+    pub fn insert(&mut self, id: &str, addr: &str, now: i64, ttl: i64) {
+        self.peers.retain(|x| x.id != id);
+        self.peers.insert(
+            0,
+            LanPeer {
+                id: id.to_owned(),
+                addr: addr.to_owned(),
+                last_seen: now,
+            },
+        );
+        self.expire(now, ttl);
+    }
+
+    pub fn expire(&mut self, now: i64, ttl: i64) {
+        self.peers.retain(|x| now - x.last_seen < ttl);
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct UserDefaultConfig {
     #[serde(default, deserialize_with = "deserialize_hashmap_string_string")]
@@ -1913,10 +2026,12 @@ impl Group {
 deserialize_default!(deserialize_string, String);
 deserialize_default!(deserialize_bool, bool);
 deserialize_default!(deserialize_i32, i32);
+deserialize_default!(deserialize_i64, i64);
 deserialize_default!(deserialize_vec_u8, Vec<u8>);
 deserialize_default!(deserialize_vec_string, Vec<String>);
 deserialize_default!(deserialize_vec_i32_string_i32, Vec<(i32, String, i32)>);
 deserialize_default!(deserialize_vec_discoverypeer, Vec<DiscoveryPeer>);
+deserialize_default!(deserialize_vec_lanpeer, Vec<LanPeer>);
 deserialize_default!(deserialize_vec_abpeer, Vec<AbPeer>);
 deserialize_default!(deserialize_vec_abentry, Vec<AbEntry>);
 deserialize_default!(deserialize_vec_groupuser, Vec<GroupUser>);
@@ -2092,6 +2207,7 @@ pub mod keys {
     pub const OPTION_ENABLE_HWCODEC: &str = "enable-hwcodec";
     pub const OPTION_APPROVE_MODE: &str = "approve-mode";
     pub const OPTION_CUSTOM_RENDEZVOUS_SERVER: &str = "custom-rendezvous-server";
+    pub const OPTION_RELAY_SERVER: &str = "relay-server";
     pub const OPTION_API_SERVER: &str = "api-server";
     pub const OPTION_KEY: &str = "key";
     pub const OPTION_PRESET_ADDRESS_BOOK_NAME: &str = "preset-address-book-name";
@@ -2099,6 +2215,56 @@ pub mod keys {
     pub const OPTION_ENABLE_DIRECTX_CAPTURE: &str = "enable-directx-capture";
     pub const OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE: &str =
         "enable-android-software-encoding-half-scale";
+    pub const OPTION_RELAY_BITRATE_CAP: &str = "relay-bitrate-cap";
+    pub const OPTION_DIRECT_BITRATE_CAP: &str = "direct-bitrate-cap";
+    pub const OPTION_SIGN_ONLINE_REQUEST: &str = "sign-online-request";
+    pub const OPTION_ONLINE_SECURE: &str = "online-secure";
+    pub const OPTION_PUNCH_HOLE_DEADLINE: &str = "punch-hole-deadline";
+    pub const OPTION_SOCKET_DSCP: &str = "socket-dscp";
+    pub const OPTION_NO_RELAY_PEERS: &str = "no-relay-peers";
+    pub const OPTION_AUTO_SELECT_FASTEST_SERVER: &str = "auto-select-fastest-server";
+    pub const OPTION_WAYLAND_TARGET_RESOLUTION: &str = "wayland-target-resolution";
+    pub const OPTION_ADDR_MANGLE_VERSION: &str = "addr-mangle-version";
+    pub const OPTION_WAYLAND_CAPTURE_WINDOW: &str = "wayland-capture-window";
+    pub const OPTION_RELAY_ALLOWLIST: &str = "relay-allowlist";
+    pub const OPTION_RENDEZVOUS_PROXY: &str = "rendezvous-proxy";
+    pub const OPTION_ID_ALIAS: &str = "id-alias";
+    pub const OPTION_NAT_TEST_TIMEOUT: &str = "nat-test-timeout";
+    pub const OPTION_RELAY_PREWARM: &str = "relay-prewarm";
+    pub const OPTION_LIGHT_REGISTER: &str = "light-register";
+    pub const OPTION_OFFLINE_GRACE_QUERIES: &str = "offline-grace-queries";
+    pub const OPTION_FORCE_RELAY: &str = "force-relay";
+    pub const OPTION_FORCE_RELAY_PEERS: &str = "force-relay-peers";
+    pub const OPTION_WAYLAND_CURSOR_OVERLAY: &str = "wayland-cursor-overlay";
+    pub const OPTION_WAYLAND_ADAPTIVE: &str = "wayland-adaptive";
+    pub const OPTION_WAYLAND_ADAPTIVE_DROP_PERCENT: &str = "wayland-adaptive-drop-percent";
+    pub const OPTION_WAYLAND_ADAPTIVE_RECOVER_PERCENT: &str = "wayland-adaptive-recover-percent";
+    pub const OPTION_MAX_ONLINE_QUERY_PEERS: &str = "max-online-query-peers";
+    pub const OPTION_RENDEZVOUS_DNS_CACHE_TTL: &str = "rendezvous-dns-cache-ttl";
+    pub const OPTION_WAYLAND_HDR_PASSTHROUGH: &str = "wayland-hdr-passthrough";
+    pub const OPTION_UUID_MISMATCH_COOLDOWN: &str = "uuid-mismatch-cooldown";
+    pub const OPTION_DEVICE_UUID: &str = "device-uuid";
+    pub const OPTION_HTTP_USER_AGENT: &str = "http-user-agent";
+    pub const OPTION_HTTP_HEADERS: &str = "http-headers";
+    pub const OPTION_RELAY_RATE_LIMIT: &str = "relay-rate-limit";
+    pub const OPTION_RELAY_RATE_LIMIT_SCOPE: &str = "relay-rate-limit-scope";
+    pub const OPTION_DIRECT_ACCESS_MAX_CONCURRENT: &str = "direct-access-max-concurrent";
+    pub const OPTION_PREFER_LAN: &str = "prefer-lan";
+    pub const OPTION_RENDEZVOUS_DEBUG_PROTO: &str = "rendezvous-debug-proto";
+    pub const OPTION_SOURCE_PORT_RANGE: &str = "source-port-range";
+    pub const OPTION_ALLOW_UNSIGNED_SERVER_CONFIG: &str = "allow-unsigned-server-config";
+    pub const OPTION_LAN_PEER_TTL: &str = "lan-peer-ttl";
+    pub const OPTION_RELAY_SEND_BUFFER_SIZE: &str = "relay-send-buffer-size";
+    pub const OPTION_RELAY_RECV_BUFFER_SIZE: &str = "relay-recv-buffer-size";
+    pub const OPTION_ONLINE_SERVER: &str = "online-server";
+    pub const OPTION_WAYLAND_PACE: &str = "wayland-pace";
+    pub const OPTION_WAYLAND_BLANK_RECTS: &str = "wayland-blank-rects";
+    pub const OPTION_RENDEZVOUS_LOG_SAMPLING: &str = "rendezvous-log-sampling";
+    pub const OPTION_LATENCY_EMA_WINDOW: &str = "latency-ema-window";
+    pub const OPTION_DIRECT_PSK: &str = "direct-psk";
+    pub const OPTION_NAT_TEST_SERVERS: &str = "nat-test-servers";
+    pub const OPTION_WAYLAND_DAMAGE: &str = "wayland-damage";
+    pub const OPTION_FRAME_BUFFER_POOL_SIZE: &str = "frame-buffer-pool-size";
 
     // flutter local options
     pub const OPTION_FLUTTER_REMOTE_MENUBAR_STATE: &str = "remoteMenubarState";
@@ -2223,6 +2389,55 @@ pub mod keys {
         OPTION_PRESET_ADDRESS_BOOK_TAG,
         OPTION_ENABLE_DIRECTX_CAPTURE,
         OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE,
+        OPTION_RELAY_BITRATE_CAP,
+        OPTION_DIRECT_BITRATE_CAP,
+        OPTION_SIGN_ONLINE_REQUEST,
+        OPTION_ONLINE_SECURE,
+        OPTION_PUNCH_HOLE_DEADLINE,
+        OPTION_SOCKET_DSCP,
+        OPTION_NO_RELAY_PEERS,
+        OPTION_AUTO_SELECT_FASTEST_SERVER,
+        OPTION_WAYLAND_TARGET_RESOLUTION,
+        OPTION_ADDR_MANGLE_VERSION,
+        OPTION_WAYLAND_CAPTURE_WINDOW,
+        OPTION_RELAY_ALLOWLIST,
+        OPTION_RENDEZVOUS_PROXY,
+        OPTION_ID_ALIAS,
+        OPTION_NAT_TEST_TIMEOUT,
+        OPTION_RELAY_PREWARM,
+        OPTION_LIGHT_REGISTER,
+        OPTION_OFFLINE_GRACE_QUERIES,
+        OPTION_FORCE_RELAY,
+        OPTION_FORCE_RELAY_PEERS,
+        OPTION_WAYLAND_CURSOR_OVERLAY,
+        OPTION_WAYLAND_ADAPTIVE,
+        OPTION_WAYLAND_ADAPTIVE_DROP_PERCENT,
+        OPTION_WAYLAND_ADAPTIVE_RECOVER_PERCENT,
+        OPTION_MAX_ONLINE_QUERY_PEERS,
+        OPTION_RENDEZVOUS_DNS_CACHE_TTL,
+        OPTION_WAYLAND_HDR_PASSTHROUGH,
+        OPTION_UUID_MISMATCH_COOLDOWN,
+        OPTION_HTTP_USER_AGENT,
+        OPTION_HTTP_HEADERS,
+        OPTION_RELAY_RATE_LIMIT,
+        OPTION_RELAY_RATE_LIMIT_SCOPE,
+        OPTION_DIRECT_ACCESS_MAX_CONCURRENT,
+        OPTION_PREFER_LAN,
+        OPTION_RENDEZVOUS_DEBUG_PROTO,
+        OPTION_SOURCE_PORT_RANGE,
+        OPTION_ALLOW_UNSIGNED_SERVER_CONFIG,
+        OPTION_LAN_PEER_TTL,
+        OPTION_RELAY_SEND_BUFFER_SIZE,
+        OPTION_RELAY_RECV_BUFFER_SIZE,
+        OPTION_ONLINE_SERVER,
+        OPTION_WAYLAND_PACE,
+        OPTION_WAYLAND_BLANK_RECTS,
+        OPTION_RENDEZVOUS_LOG_SAMPLING,
+        OPTION_LATENCY_EMA_WINDOW,
+        OPTION_DIRECT_PSK,
+        OPTION_NAT_TEST_SERVERS,
+        OPTION_WAYLAND_DAMAGE,
+        OPTION_FRAME_BUFFER_POOL_SIZE,
     ];
 }
 
@@ -2242,6 +2457,43 @@ pub fn common_store<T: serde::Serialize>(config: &T, suffix: &str) {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_resolve_rendezvous_servers() {
+        let s = |x: &str| x.to_owned();
+        let pushed = Some(s("rs1.example.com,rs2.example.com"));
+        assert_eq!(
+            resolve_rendezvous_servers(s(""), s(""), s(""), pushed.clone()),
+            (
+                vec![s("rs1.example.com"), s("rs2.example.com")],
+                RendezvousServerSource::Pushed
+            )
+        );
+        assert_eq!(
+            resolve_rendezvous_servers(s(""), s(""), s("env.example.com"), pushed.clone()),
+            (vec![s("env.example.com")], RendezvousServerSource::Env)
+        );
+        assert_eq!(
+            resolve_rendezvous_servers(s(""), s("my.example.com"), s("env.example.com"), pushed),
+            (vec![s("my.example.com")], RendezvousServerSource::Config)
+        );
+        assert_eq!(
+            resolve_rendezvous_servers(s("exe.example.com"), s("my.example.com"), s(""), None),
+            (
+                vec![s("exe.example.com")],
+                RendezvousServerSource::Executable
+            )
+        );
+        // an empty or invalid push falls back to the built-in servers
+        assert_eq!(
+            resolve_rendezvous_servers(s(""), s(""), s(""), Some(s("invalid"))).1,
+            RendezvousServerSource::Default
+        );
+        assert_eq!(
+            resolve_rendezvous_servers(s(""), s(""), s(""), None).1,
+            RendezvousServerSource::Default
+        );
+    }
+
     #[test]
     fn test_serialize() {
         let cfg: Config = Default::default();
@@ -2252,6 +2504,28 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_recent_lan_peers() {
+        let mut recent = RecentLanPeers::default();
+        recent.insert("1", "192.168.1.2", 1000, 500);
+        recent.insert("2", "192.168.1.3", 1200, 500);
+        recent.insert("1", "192.168.1.4", 1300, 500);
+        let ids: Vec<_> = recent.peers.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2"]);
+        assert_eq!(recent.peers[0].addr, "192.168.1.4");
+        assert_eq!(recent.peers[0].last_seen, 1300);
+        recent.expire(1699, 500);
+        assert_eq!(recent.peers.len(), 2);
+        recent.expire(1700, 500);
+        assert_eq!(recent.peers.len(), 1);
+        recent.insert("3", "192.168.1.5", 2000, 500);
+        let ids: Vec<_> = recent.peers.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, vec!["3"]);
+        let res = toml::to_string_pretty(&recent).unwrap();
+        let loaded: RecentLanPeers = toml::from_str(&res).unwrap();
+        assert_eq!(loaded.peers, recent.peers);
+    }
+
     #[test]
     fn test_overwrite_settings() {
         DEFAULT_SETTINGS
@@ -19,6 +19,7 @@ pub use tokio_util;
 pub mod proxy;
 pub mod socket_client;
 pub mod tcp;
+pub mod throttle;
 pub mod udp;
 pub use env_logger;
 pub use log;
@@ -105,6 +106,15 @@ pub type ResultType<F, E = anyhow::Error> = anyhow::Result<F, E>;
 
 pub struct AddrMangle();
 
+/// The `AddrMangle` scheme used by a rendezvous server, forks of hbbs may not use the default one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddrMangleVersion {
+    #[default]
+    V0,
+    /// ip octets followed by the little endian port, without obfuscation
+    Plain,
+}
+
 #[inline]
 pub fn try_into_v4(addr: SocketAddr) -> SocketAddr {
     match addr {
@@ -177,6 +187,38 @@ impl AddrMangle {
             port as u16,
         ))
     }
+
+    pub fn encode_with(addr: SocketAddr, version: AddrMangleVersion) -> Vec<u8> {
+        match version {
+            AddrMangleVersion::V0 => Self::encode(addr),
+            AddrMangleVersion::Plain => {
+                let addr = try_into_v4(addr);
+                let mut x = match addr.ip() {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                x.extend(addr.port().to_le_bytes());
+                x
+            }
+        }
+    }
+
+    pub fn decode_with(bytes: &[u8], version: AddrMangleVersion) -> SocketAddr {
+        match version {
+            AddrMangleVersion::V0 => Self::decode(bytes),
+            AddrMangleVersion::Plain => match bytes.len() {
+                6 => {
+                    let port = u16::from_le_bytes([bytes[4], bytes[5]]);
+                    SocketAddr::V4(SocketAddrV4::new(
+                        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+                        port,
+                    ))
+                }
+                18 => Self::decode(bytes),
+                _ => Config::get_any_listen_addr(false),
+            },
+        }
+    }
 }
 
 pub fn get_version_from_url(url: &str) -> String {
@@ -292,7 +334,33 @@ pub fn get_exe_time() -> SystemTime {
     })
 }
 
+// 32 hex digits, with or without the dashes of the 8-4-4-4-12 form.
+fn is_valid_device_uuid(uuid: &str) -> bool {
+    let hex = if uuid.len() == 36 {
+        if [8, 13, 18, 23].iter().any(|&i| uuid.as_bytes()[i] != b'-') {
+            return false;
+        }
+        uuid.replace('-', "")
+    } else {
+        uuid.to_owned()
+    };
+    hex.len() == 32 && hex.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
 pub fn get_uuid() -> Vec<u8> {
+    // Set it if cloned machines share the machine id, only on this machine, never pushed with
+    // the settings of a custom client, otherwise all of its machines share one.
+    let device_uuid = Config::get_saved_option(config::keys::OPTION_DEVICE_UUID);
+    if !device_uuid.is_empty() {
+        if is_valid_device_uuid(&device_uuid) {
+            return device_uuid.into();
+        }
+        log::warn!(
+            "Ignore the invalid {} {:?}, expected 32 hex digits",
+            config::keys::OPTION_DEVICE_UUID,
+            device_uuid
+        );
+    }
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     if let Ok(id) = machine_uid::get() {
         return id.into();
@@ -410,6 +478,49 @@ mod test {
         assert_eq!(addr, AddrMangle::decode(&AddrMangle::encode(addr)));
     }
 
+    #[test]
+    fn test_mangle_plain() {
+        let plain = AddrMangleVersion::Plain;
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 16, 32), 21116));
+        let bytes = AddrMangle::encode_with(addr, plain);
+        assert_eq!(bytes, vec![192, 168, 16, 32, 0x7c, 0x52]);
+        assert_eq!(addr, AddrMangle::decode_with(&bytes, plain));
+
+        let addr = "[2001:db8::1]:8080".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            addr,
+            AddrMangle::decode_with(&AddrMangle::encode_with(addr, plain), plain)
+        );
+
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 21118));
+        assert_eq!(
+            AddrMangle::encode_with(addr, AddrMangleVersion::V0).len(),
+            AddrMangle::encode(addr).len()
+        );
+        assert_eq!(
+            AddrMangle::decode_with(&[1, 2, 3], plain),
+            Config::get_any_listen_addr(false)
+        );
+    }
+
+    #[test]
+    fn test_mangle_ipv6() {
+        for version in [AddrMangleVersion::V0, AddrMangleVersion::Plain] {
+            for addr in ["[::1]:0", "[fe80::1:2]:65535", "[2001:db8::]:21116"] {
+                let addr = addr.parse::<SocketAddr>().unwrap();
+                let bytes = AddrMangle::encode_with(addr, version);
+                assert_eq!(bytes.len(), 18);
+                assert_eq!(addr, AddrMangle::decode_with(&bytes, version));
+            }
+            // sent as ipv4
+            let mapped = "[::ffff:192.168.1.2]:21116".parse::<SocketAddr>().unwrap();
+            assert_eq!(
+                AddrMangle::decode_with(&AddrMangle::encode_with(mapped, version), version),
+                "192.168.1.2:21116".parse::<SocketAddr>().unwrap()
+            );
+        }
+    }
+
     #[test]
     fn test_allow_err() {
         allow_err!(Err("test err") as Result<(), &str>);
@@ -489,6 +600,21 @@ mod test {
         assert_eq!(AddrMangle::decode(&AddrMangle::encode(addr_v6)), addr_v6);
     }
 
+    #[test]
+    fn test_is_valid_device_uuid() {
+        assert!(is_valid_device_uuid("0123456789abcdef0123456789ABCDEF"));
+        assert!(is_valid_device_uuid("01234567-89ab-cdef-0123-456789abcdef"));
+        assert!(!is_valid_device_uuid(""));
+        assert!(!is_valid_device_uuid("my-machine"));
+        assert!(!is_valid_device_uuid("0123456789abcdef0123456789abcdeg"));
+        assert!(!is_valid_device_uuid(
+            "0123456-789ab-cdef-0123-456789abcdef"
+        ));
+        assert!(!is_valid_device_uuid(
+            "01234567-89ab-cdef-0123-456789abcdef0"
+        ));
+    }
+
     #[test]
     fn test_get_version_number() {
         assert_eq!(get_version_number("1.1.10"), 1001100);
@@ -1,12 +1,17 @@
 use crate::{
-    config::{Config, NetworkType},
-    tcp::FramedStream,
+    bail,
+    config::{keys, Config, NetworkType, Socks5Server},
+    tcp::{ConnectOptions, FramedStream, SocketBuffers},
     udp::FramedSocket,
     ResultType,
 };
 use anyhow::Context;
-use std::net::SocketAddr;
-use tokio::net::ToSocketAddrs;
+use rand::Rng;
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+};
+use tokio::net::{TcpSocket, ToSocketAddrs};
 use tokio_socks::{IntoTargetAddr, TargetAddr};
 
 #[inline]
@@ -49,6 +54,181 @@ pub fn increase_port<T: std::string::ToString>(host: T, offset: i32) -> String {
     host
 }
 
+/// Like `increase_port`, but fails instead of returning an address with a port out of
+/// 1..=65535. `host` without a port is returned as is.
+pub fn checked_increase_port<T: std::string::ToString>(host: T, offset: i32) -> ResultType<String> {
+    let host = host.to_string();
+    let port = if crate::is_ipv6_str(&host) {
+        host.split_once("]:")
+            .filter(|_| host.starts_with('['))
+            .map(|x| x.1)
+    } else {
+        host.split_once(':')
+            .filter(|x| !x.1.contains(':'))
+            .map(|x| x.1)
+    };
+    let Some(port) = port else {
+        return Ok(host);
+    };
+    let port: i32 = port.parse().unwrap_or(0);
+    if !(1..=65535).contains(&port) {
+        bail!("Invalid port in {}", host);
+    }
+    if !(1..=65535).contains(&(port + offset)) {
+        bail!("Port {} + {} of {} is out of range", port, offset, host);
+    }
+    Ok(increase_port(host, offset))
+}
+
+// IP_TOS value from the configured DSCP code point, e.g. 46 for expedited forwarding.
+#[inline]
+pub fn get_tos() -> Option<u32> {
+    dscp_to_tos(&Config::get_option(keys::OPTION_SOCKET_DSCP))
+}
+
+fn dscp_to_tos(dscp: &str) -> Option<u32> {
+    let dscp = dscp.trim();
+    if dscp.is_empty() {
+        return None;
+    }
+    match dscp.parse::<u32>() {
+        Ok(0) => None,
+        Ok(v) if v <= 63 => Some(v << 2),
+        _ => {
+            log::warn!(
+                "Invalid {} {:?}, expect 0-63",
+                keys::OPTION_SOCKET_DSCP,
+                dscp
+            );
+            None
+        }
+    }
+}
+
+/// Calls `set` with `tos` if there is one, a failure is only logged, e.g. on a platform
+/// without IP_TOS. Returns whether it is set.
+pub fn apply_tos(tos: Option<u32>, set: impl FnOnce(u32) -> std::io::Result<()>) -> bool {
+    let Some(tos) = tos else {
+        return false;
+    };
+    match set(tos) {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!(
+                "Failed to set IP_TOS {} of {}: {}",
+                tos,
+                keys::OPTION_SOCKET_DSCP,
+                err
+            );
+            false
+        }
+    }
+}
+
+// Ports outbound tcp connections are bound to, e.g. "50000-50100" or a single "50000".
+#[inline]
+pub fn get_source_port_range() -> Option<RangeInclusive<u16>> {
+    parse_port_range(&Config::get_option(keys::OPTION_SOURCE_PORT_RANGE))
+}
+
+fn parse_port_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let range = range.trim();
+    if range.is_empty() {
+        return None;
+    }
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let start = start.trim().parse::<u16>().ok()?;
+    let end = end.trim().parse::<u16>().ok()?;
+    if start == 0 || start > end {
+        return None;
+    }
+    Some(start..=end)
+}
+
+/// Binds a tcp socket on `ip` to a free port of `ports`, starting from a random one so that
+/// concurrent connections don't race for the same port. `None` if all of them are in use.
+pub fn bind_in_range(ip: IpAddr, ports: RangeInclusive<u16>) -> Option<TcpSocket> {
+    let (start, end) = (*ports.start(), *ports.end());
+    let len = (end - start) as u32 + 1;
+    let offset = rand::thread_rng().gen_range(0..len);
+    for i in 0..len {
+        let port = start + ((offset + i) % len) as u16;
+        let socket = if ip.is_ipv4() {
+            TcpSocket::new_v4()
+        } else {
+            TcpSocket::new_v6()
+        };
+        let Ok(socket) = socket else {
+            return None;
+        };
+        // no reuse, a port is only free if nothing else is bound to it
+        if socket.bind(SocketAddr::new(ip, port)).is_ok() {
+            return Some(socket);
+        }
+    }
+    None
+}
+
+const MIN_SOCKET_BUFFER: u32 = 4 * 1024;
+const MAX_SOCKET_BUFFER: u32 = 64 * 1024 * 1024;
+
+/// `relay-send-buffer-size` and `relay-recv-buffer-size` in bytes for the relay connections,
+/// the OS defaults if unset or invalid.
+pub fn get_relay_buffers() -> SocketBuffers {
+    SocketBuffers {
+        send: parse_buffer_size(
+            &Config::get_option(keys::OPTION_RELAY_SEND_BUFFER_SIZE),
+            os_buffer_max("wmem_max"),
+        ),
+        recv: parse_buffer_size(
+            &Config::get_option(keys::OPTION_RELAY_RECV_BUFFER_SIZE),
+            os_buffer_max("rmem_max"),
+        ),
+    }
+}
+
+fn parse_buffer_size(size: &str, os_max: Option<u32>) -> Option<u32> {
+    let size = size.trim();
+    if size.is_empty() {
+        return None;
+    }
+    match size.parse::<u32>() {
+        Ok(n) if (MIN_SOCKET_BUFFER..=MAX_SOCKET_BUFFER).contains(&n) => {
+            if let Some(max) = os_max.filter(|max| n > *max) {
+                log::warn!(
+                    "Socket buffer size {} is above the OS limit {}, it will be clamped",
+                    n,
+                    max
+                );
+            }
+            Some(n)
+        }
+        _ => {
+            log::warn!(
+                "Invalid socket buffer size {:?}, expected {}..={} bytes, use the OS default",
+                size,
+                MIN_SOCKET_BUFFER,
+                MAX_SOCKET_BUFFER
+            );
+            None
+        }
+    }
+}
+
+// The largest buffer an unprivileged process may request, `None` if unknown.
+fn os_buffer_max(_name: &str) -> Option<u32> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_to_string(format!("/proc/sys/net/core/{}", _name))
+            .ok()
+            .and_then(|x| x.trim().parse().ok())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
 pub fn test_if_valid_server(host: &str, test_with_proxy: bool) -> String {
     let host = check_port(host, 0);
     use std::net::ToSocketAddrs;
@@ -114,18 +294,76 @@ pub async fn connect_tcp_local<
     local: Option<SocketAddr>,
     ms_timeout: u64,
 ) -> ResultType<FramedStream> {
-    if let Some(conf) = Config::get_socks() {
+    connect_tcp_via(target, local, Config::get_socks(), ms_timeout).await
+}
+
+/// Like `connect_tcp_local`, but with `options` for the socket. They are not applied through
+/// a proxy.
+pub async fn connect_tcp_with_options<
+    't,
+    T: IntoTargetAddr<'t> + ToSocketAddrs + IsResolvedSocketAddr + std::fmt::Display,
+>(
+    target: T,
+    local: Option<SocketAddr>,
+    ms_timeout: u64,
+    options: ConnectOptions,
+) -> ResultType<FramedStream> {
+    connect_tcp_via_with_options(target, local, Config::get_socks(), ms_timeout, options).await
+}
+
+/// Like `connect_tcp_local`, but through `proxy` instead of the global proxy, direct if `None`.
+pub async fn connect_tcp_via<
+    't,
+    T: IntoTargetAddr<'t> + ToSocketAddrs + IsResolvedSocketAddr + std::fmt::Display,
+>(
+    target: T,
+    local: Option<SocketAddr>,
+    proxy: Option<Socks5Server>,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    connect_tcp_via_with_options(target, local, proxy, ms_timeout, Default::default()).await
+}
+
+/// Like `connect_tcp_via` without a local address, but bound to a port of
+/// `source-port-range` if it is set and there is no proxy.
+pub async fn connect_tcp_in_range<
+    't,
+    T: IntoTargetAddr<'t> + ToSocketAddrs + IsResolvedSocketAddr + std::fmt::Display,
+>(
+    target: T,
+    proxy: Option<Socks5Server>,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    let options = ConnectOptions {
+        source_ports: get_source_port_range(),
+        ..Default::default()
+    };
+    connect_tcp_via_with_options(target, None, proxy, ms_timeout, options).await
+}
+
+async fn connect_tcp_via_with_options<
+    't,
+    T: IntoTargetAddr<'t> + ToSocketAddrs + IsResolvedSocketAddr + std::fmt::Display,
+>(
+    target: T,
+    local: Option<SocketAddr>,
+    proxy: Option<Socks5Server>,
+    ms_timeout: u64,
+    options: ConnectOptions,
+) -> ResultType<FramedStream> {
+    if let Some(conf) = proxy {
         return FramedStream::connect(target, local, &conf, ms_timeout).await;
     }
     if let Some(target) = target.resolve() {
         if let Some(local) = local {
             if local.is_ipv6() && target.is_ipv4() {
                 let target = query_nip_io(target).await?;
-                return FramedStream::new(target, Some(local), ms_timeout).await;
+                return FramedStream::new_with_options(target, Some(local), ms_timeout, options)
+                    .await;
             }
         }
     }
-    FramedStream::new(target, local, ms_timeout).await
+    FramedStream::new_with_options(target, local, ms_timeout, options).await
 }
 
 #[inline]
@@ -171,20 +409,33 @@ pub async fn new_udp_for(
     target: &str,
     ms_timeout: u64,
 ) -> ResultType<(FramedSocket, TargetAddr<'static>)> {
-    let (ipv4, target) = if NetworkType::Direct == Config::get_network_type() {
+    new_udp_for_via(target, Config::get_socks(), ms_timeout).await
+}
+
+/// Like `new_udp_for`, but through `proxy` instead of the global proxy, direct if `None`.
+pub async fn new_udp_for_via(
+    target: &str,
+    proxy: Option<Socks5Server>,
+    ms_timeout: u64,
+) -> ResultType<(FramedSocket, TargetAddr<'static>)> {
+    let (ipv4, target) = if proxy.is_none() {
         let addr = test_target(target).await?;
         (addr.is_ipv4(), addr.into_target_addr()?)
     } else {
         (true, target.into_target_addr()?)
     };
     Ok((
-        new_udp(Config::get_any_listen_addr(ipv4), ms_timeout).await?,
+        new_udp(Config::get_any_listen_addr(ipv4), proxy, ms_timeout).await?,
         target.to_owned(),
     ))
 }
 
-async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<FramedSocket> {
-    match Config::get_socks() {
+async fn new_udp<T: ToSocketAddrs>(
+    local: T,
+    proxy: Option<Socks5Server>,
+    ms_timeout: u64,
+) -> ResultType<FramedSocket> {
+    match proxy {
         None => Ok(FramedSocket::new(local).await?),
         Some(conf) => {
             let socket = FramedSocket::new_proxy(
@@ -203,7 +454,15 @@ async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<Fram
 pub async fn rebind_udp_for(
     target: &str,
 ) -> ResultType<Option<(FramedSocket, TargetAddr<'static>)>> {
-    if Config::get_network_type() != NetworkType::Direct {
+    rebind_udp_for_via(target, Config::get_network_type() != NetworkType::Direct).await
+}
+
+/// Like `rebind_udp_for`, nothing is rebound if the socket goes through a proxy.
+pub async fn rebind_udp_for_via(
+    target: &str,
+    is_proxy: bool,
+) -> ResultType<Option<(FramedSocket, TargetAddr<'static>)>> {
+    if is_proxy {
         return Ok(None);
     }
     let addr = test_target(target).await?;
@@ -271,6 +530,128 @@ mod tests {
         assert!(test_if_valid_server_for_proxy_("abcd.com:1").is_empty());
     }
 
+    #[test]
+    fn test_dscp_to_tos() {
+        assert_eq!(dscp_to_tos(""), None);
+        assert_eq!(dscp_to_tos("0"), None);
+        assert_eq!(dscp_to_tos("64"), None);
+        assert_eq!(dscp_to_tos("ef"), None);
+        assert_eq!(dscp_to_tos("46"), Some(184));
+        assert_eq!(dscp_to_tos(" 8"), Some(32));
+    }
+
+    #[test]
+    fn test_apply_tos() {
+        let mut called = None;
+        assert!(!apply_tos(None, |tos| {
+            called = Some(tos);
+            Ok(())
+        }));
+        assert_eq!(called, None);
+        assert!(apply_tos(Some(184), |tos| {
+            called = Some(tos);
+            Ok(())
+        }));
+        assert_eq!(called, Some(184));
+        assert!(!apply_tos(Some(184), |_| Err(
+            std::io::ErrorKind::Unsupported.into()
+        )));
+
+        #[cfg(target_os = "linux")]
+        {
+            let socket = TcpSocket::new_v4().unwrap();
+            assert!(apply_tos(dscp_to_tos("46"), |tos| socket.set_tos(tos)));
+            assert_eq!(socket.tos().unwrap(), 184);
+        }
+    }
+
+    #[test]
+    fn test_port_range() {
+        assert_eq!(parse_port_range(""), None);
+        assert_eq!(parse_port_range("0-10"), None);
+        assert_eq!(parse_port_range("100-10"), None);
+        assert_eq!(parse_port_range("100-70000"), None);
+        assert_eq!(parse_port_range("50000"), Some(50000..=50000));
+        assert_eq!(parse_port_range(" 50000 - 50100"), Some(50000..=50100));
+
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let listener = std::net::TcpListener::bind((ip, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(bind_in_range(ip, port..=port).is_none());
+        drop(listener);
+        let socket = bind_in_range(ip, port..=port).unwrap();
+        assert_eq!(socket.local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_source_ports_opt_in() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let options = ConnectOptions {
+            source_ports: Some(port..=port),
+            ..Default::default()
+        };
+        let local = "127.0.0.1:0".parse().ok();
+        let stream = FramedStream::new_with_options(target, local, 1000, options.clone())
+            .await
+            .unwrap();
+        assert_ne!(stream.local_addr().port(), port);
+        let stream = FramedStream::new_with_options(target, None, 1000, options)
+            .await
+            .unwrap();
+        assert_eq!(stream.local_addr().port(), port);
+        drop(stream);
+        let stream = FramedStream::new(target, None, 1000).await.unwrap();
+        assert_ne!(stream.local_addr().port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_size() {
+        assert_eq!(parse_buffer_size("", None), None);
+        assert_eq!(parse_buffer_size("abc", None), None);
+        assert_eq!(parse_buffer_size("1024", None), None);
+        assert_eq!(parse_buffer_size("134217728", None), None);
+        assert_eq!(parse_buffer_size(" 65536 ", None), Some(65536));
+        // clamped by the OS, but still requested
+        assert_eq!(parse_buffer_size("4194304", Some(212992)), Some(4194304));
+
+        let buffers = SocketBuffers {
+            send: Some(64 * 1024),
+            recv: None,
+        };
+        let socket = TcpSocket::new_v4().unwrap();
+        let default_recv = socket.recv_buffer_size().unwrap();
+        buffers.apply(&socket);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert_eq!(socket.recv_buffer_size().unwrap(), default_recv);
+    }
+
+    #[test]
+    fn test_checked_increase_port() {
+        assert_eq!(
+            checked_increase_port("test.com:13", 1).unwrap(),
+            "test.com:14"
+        );
+        assert_eq!(checked_increase_port("[1:2]:12", -1).unwrap(), "[1:2]:11");
+        assert_eq!(checked_increase_port("test.com", 1).unwrap(), "test.com");
+        assert_eq!(checked_increase_port("1:2", 1).unwrap(), "1:2");
+        assert_eq!(
+            checked_increase_port("test.com:65534", 1).unwrap(),
+            "test.com:65535"
+        );
+        assert!(checked_increase_port("test.com:65535", 1).is_err());
+        assert!(checked_increase_port("[1:2]:65535", 1).is_err());
+        assert!(checked_increase_port("test.com:1", -1).is_err());
+        assert!(checked_increase_port("test.com:0", 1).is_err());
+        assert!(checked_increase_port("test.com:70000", -10000).is_err());
+        assert!(checked_increase_port("test.com:x", 1).is_err());
+    }
+
     #[test]
     fn test_check_port() {
         assert_eq!(check_port("[1:2]:12", 32), "[1:2]:12");
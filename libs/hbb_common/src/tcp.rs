@@ -10,7 +10,7 @@ use sodiumoxide::crypto::{
 use std::{
     io::{self, Error, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, RangeInclusive},
     pin::Pin,
     task::{Context, Poll},
 };
@@ -19,7 +19,7 @@ use tokio::{
     net::{lookup_host, TcpListener, TcpSocket, ToSocketAddrs},
 };
 use tokio_socks::IntoTargetAddr;
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Framed, FramedParts};
 
 pub trait TcpStreamTrait: AsyncRead + AsyncWrite + Unpin {}
 pub struct DynTcpStream(pub(crate) Box<dyn TcpStreamTrait + Send + Sync>);
@@ -79,11 +79,85 @@ pub(crate) fn new_socket(addr: std::net::SocketAddr, reuse: bool) -> Result<TcpS
     Ok(socket)
 }
 
+/// SO_SNDBUF and SO_RCVBUF of a socket in bytes, the OS default if `None`.
+///
+/// The kernel allocates them for every connection, e.g. 4 MiB each for 100 relayed sessions
+/// is up to 800 MiB, Linux even doubles the value for its bookkeeping.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SocketBuffers {
+    pub send: Option<u32>,
+    pub recv: Option<u32>,
+}
+
+impl SocketBuffers {
+    pub fn is_default(&self) -> bool {
+        self.send.is_none() && self.recv.is_none()
+    }
+
+    // Before connecting, the receive buffer limits the window scale negotiated in the handshake.
+    pub(crate) fn apply(&self, socket: &TcpSocket) {
+        if self.is_default() {
+            return;
+        }
+        if let Some(size) = self.send {
+            if let Err(err) = socket.set_send_buffer_size(size) {
+                log::warn!("Failed to set the send buffer size to {}: {}", size, err);
+            }
+        }
+        if let Some(size) = self.recv {
+            if let Err(err) = socket.set_recv_buffer_size(size) {
+                log::warn!("Failed to set the receive buffer size to {}: {}", size, err);
+            }
+        }
+        // the OS may clamp or round them
+        log::debug!(
+            "Socket buffers {:?}, applied send: {:?}, recv: {:?}",
+            self,
+            socket.send_buffer_size().ok(),
+            socket.recv_buffer_size().ok()
+        );
+    }
+}
+
+/// Options of an outbound socket set before it connects, none of them apply through a proxy.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConnectOptions {
+    pub buffers: SocketBuffers,
+    /// IP_TOS, e.g. `socket_client::get_tos()` for the relay and direct connections.
+    pub tos: Option<u32>,
+    /// Source ports to bind to if there is no local address, e.g.
+    /// `socket_client::get_source_port_range()` for the rendezvous connections.
+    pub source_ports: Option<RangeInclusive<u16>>,
+}
+
+// Bound to a port of `ports` if set, an ephemeral one otherwise.
+fn new_outbound_socket(
+    local: SocketAddr,
+    ports: Option<RangeInclusive<u16>>,
+) -> Result<TcpSocket, std::io::Error> {
+    if let Some(ports) = ports {
+        if let Some(socket) = crate::socket_client::bind_in_range(local.ip(), ports.clone()) {
+            return Ok(socket);
+        }
+        log::warn!("No free source port in {:?}, use an ephemeral one", ports);
+    }
+    new_socket(local, true)
+}
+
 impl FramedStream {
     pub async fn new<T: ToSocketAddrs + std::fmt::Display>(
         remote_addr: T,
         local_addr: Option<SocketAddr>,
         ms_timeout: u64,
+    ) -> ResultType<Self> {
+        Self::new_with_options(remote_addr, local_addr, ms_timeout, Default::default()).await
+    }
+
+    pub async fn new_with_options<T: ToSocketAddrs + std::fmt::Display>(
+        remote_addr: T,
+        local_addr: Option<SocketAddr>,
+        ms_timeout: u64,
+        options: ConnectOptions,
     ) -> ResultType<Self> {
         for remote_addr in lookup_host(&remote_addr).await? {
             let local = if let Some(addr) = local_addr {
@@ -91,7 +165,14 @@ impl FramedStream {
             } else {
                 crate::config::Config::get_any_listen_addr(remote_addr.is_ipv4())
             };
-            if let Ok(socket) = new_socket(local, true) {
+            let ports = if local_addr.is_none() {
+                options.source_ports.clone()
+            } else {
+                None
+            };
+            if let Ok(socket) = new_outbound_socket(local, ports) {
+                set_tos(&socket, options.tos);
+                options.buffers.apply(&socket);
                 if let Ok(Ok(stream)) =
                     super::timeout(ms_timeout, socket.connect(remote_addr)).await
                 {
@@ -139,6 +220,19 @@ impl FramedStream {
         )
     }
 
+    /// Wraps the underlying stream, e.g. to throttle it. The buffered data is kept.
+    pub fn map_stream<S, F>(self, f: F) -> Self
+    where
+        S: TcpStreamTrait + Send + Sync + 'static,
+        F: FnOnce(DynTcpStream) -> S,
+    {
+        let parts = self.0.into_parts();
+        let mut mapped = FramedParts::new(DynTcpStream(Box::new(f(parts.io))), parts.codec);
+        mapped.read_buf = parts.read_buf;
+        mapped.write_buf = parts.write_buf;
+        Self(Framed::from_parts(mapped), self.1, self.2, self.3)
+    }
+
     pub fn set_raw(&mut self) {
         self.0.codec_mut().set_raw();
         self.2 = None;
@@ -208,6 +302,25 @@ impl FramedStream {
 
 const DEFAULT_BACKLOG: u32 = 128;
 
+// Accepted connections inherit IP_TOS of the listening socket.
+fn set_tos(socket: &TcpSocket, tos: Option<u32>) -> bool {
+    crate::socket_client::apply_tos(tos, |tos| socket.set_tos(tos))
+}
+
+/// `new_listener` with reuse and IP_TOS `tos` for the accepted connections.
+pub async fn new_listener_with_tos<T: ToSocketAddrs>(
+    addr: T,
+    tos: Option<u32>,
+) -> ResultType<TcpListener> {
+    let addr = lookup_host(&addr)
+        .await?
+        .next()
+        .context("could not resolve to any address")?;
+    let socket = new_socket(addr, true)?;
+    set_tos(&socket, tos);
+    socket.listen(DEFAULT_BACKLOG).map_err(anyhow::Error::msg)
+}
+
 pub async fn new_listener<T: ToSocketAddrs>(addr: T, reuse: bool) -> ResultType<TcpListener> {
     if !reuse {
         Ok(TcpListener::bind(addr).await?)
@@ -222,7 +335,9 @@ pub async fn new_listener<T: ToSocketAddrs>(addr: T, reuse: bool) -> ResultType<
     }
 }
 
-pub async fn listen_any(port: u16) -> ResultType<TcpListener> {
+/// Listens on `port` of all interfaces, IPv6 and IPv4 if possible, with IP_TOS `tos` for
+/// the accepted connections.
+pub async fn listen_any(port: u16, tos: Option<u32>) -> ResultType<TcpListener> {
     if let Ok(mut socket) = TcpSocket::new_v6() {
         #[cfg(unix)]
         {
@@ -246,16 +361,18 @@ pub async fn listen_any(port: u16) -> ResultType<TcpListener> {
             .bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))
             .is_ok()
         {
+            set_tos(&socket, tos);
             if let Ok(l) = socket.listen(DEFAULT_BACKLOG) {
                 return Ok(l);
             }
         }
     }
-    Ok(new_socket(
+    let socket = new_socket(
         SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
         true,
-    )?
-    .listen(DEFAULT_BACKLOG)?)
+    )?;
+    set_tos(&socket, tos);
+    Ok(socket.listen(DEFAULT_BACKLOG)?)
 }
 
 impl Unpin for DynTcpStream {}
@@ -0,0 +1,227 @@
+use futures::ready;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+// Wake-ups per second at most while waiting for the bucket to refill.
+const MAX_WAKEUPS: u64 = 20;
+
+/// Allows `rate` bytes per second, with a burst of one second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        let add = (elapsed.as_micros() * self.rate as u128 / 1_000_000) as u64;
+        if add > 0 {
+            self.tokens = self.rate.min(self.tokens.saturating_add(add));
+            self.last = now;
+        }
+    }
+
+    /// Up to `want` bytes which may be transferred now, or how long to wait for more.
+    pub fn take(&mut self, want: usize, now: Instant) -> Result<usize, Duration> {
+        self.refill(now);
+        if want == 0 {
+            return Ok(0);
+        }
+        if self.tokens == 0 {
+            let chunk = (want as u64).min(self.rate / MAX_WAKEUPS).max(1);
+            return Err(Duration::from_micros(chunk * 1_000_000 / self.rate));
+        }
+        let n = self.tokens.min(want as u64);
+        self.tokens -= n;
+        Ok(n as _)
+    }
+
+    /// Returns the bytes taken but not transferred.
+    pub fn give_back(&mut self, n: usize) {
+        self.tokens = self.rate.min(self.tokens.saturating_add(n as u64));
+    }
+}
+
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+/// Counts the bytes going through `inner` into `bytes` and, with a bucket, limits their rate.
+///
+/// Both directions share the bucket. A stream out of tokens returns `Pending` until it refills,
+/// which pushes back to the sender instead of buffering.
+pub struct ThrottledStream<S> {
+    inner: S,
+    bucket: Option<SharedBucket>,
+    bytes: Arc<AtomicU64>,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    pub fn new(inner: S, bucket: Option<SharedBucket>, bytes: Arc<AtomicU64>) -> Self {
+        Self {
+            inner,
+            bucket,
+            bytes,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+fn poll_budget(
+    bucket: &Option<SharedBucket>,
+    wait: &mut Option<Pin<Box<Sleep>>>,
+    want: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    let Some(bucket) = bucket else {
+        return Poll::Ready(want);
+    };
+    loop {
+        if let Some(sleep) = wait.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            *wait = None;
+        }
+        match bucket.lock().unwrap().take(want, Instant::now()) {
+            Ok(n) => return Poll::Ready(n),
+            Err(d) => *wait = Some(Box::pin(tokio::time::sleep(d))),
+        }
+    }
+}
+
+fn give_back(bucket: &Option<SharedBucket>, n: usize) {
+    if let (Some(bucket), true) = (bucket, n > 0) {
+        bucket.lock().unwrap().give_back(n);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let n = ready!(poll_budget(
+            &this.bucket,
+            &mut this.read_wait,
+            buf.remaining(),
+            cx
+        ));
+        let mut limited = buf.take(n);
+        let res = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let filled = limited.filled().len();
+        // Safety: the inner stream initialized `filled` bytes of the unfilled part of `buf`.
+        unsafe { buf.assume_init(filled) };
+        buf.advance(filled);
+        give_back(&this.bucket, n - filled);
+        this.bytes.fetch_add(filled as _, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(poll_budget(
+            &this.bucket,
+            &mut this.write_wait,
+            buf.len(),
+            cx
+        ));
+        let res = Pin::new(&mut this.inner).poll_write(cx, &buf[..n]);
+        let written = match &res {
+            Poll::Ready(Ok(written)) => *written,
+            _ => 0,
+        };
+        give_back(&this.bucket, n - written);
+        this.bytes.fetch_add(written as _, Ordering::Relaxed);
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.last;
+        assert_eq!(bucket.take(600, start), Ok(600));
+        assert_eq!(bucket.take(600, start), Ok(400));
+        // empty, wait for 1000 / MAX_WAKEUPS bytes
+        assert_eq!(bucket.take(600, start), Err(Duration::from_millis(50)));
+        assert_eq!(bucket.take(10, start), Err(Duration::from_millis(10)));
+        assert_eq!(bucket.take(0, start), Ok(0));
+        assert_eq!(
+            bucket.take(600, start + Duration::from_millis(100)),
+            Ok(100)
+        );
+        bucket.give_back(40);
+        assert_eq!(bucket.take(600, start + Duration::from_millis(100)), Ok(40));
+        // the burst is capped at one second
+        assert_eq!(bucket.take(5000, start + Duration::from_secs(10)), Ok(1000));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_stream() {
+        let (a, mut b) = tokio::io::duplex(64 * 1024);
+        let bytes = Arc::new(AtomicU64::new(0));
+        let bucket = Arc::new(Mutex::new(TokenBucket::new(10_000)));
+        let mut a = ThrottledStream::new(a, Some(bucket), bytes.clone());
+        let start = Instant::now();
+        let data = vec![0u8; 15_000];
+        let writer = async {
+            a.write_all(&data).await.unwrap();
+            a.flush().await.unwrap();
+        };
+        let mut received = vec![0u8; data.len()];
+        let (_, read) = tokio::join!(writer, b.read_exact(&mut received));
+        read.unwrap();
+        // a burst of 10_000 bytes, the rest at 10_000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        assert_eq!(bytes.load(Ordering::Relaxed), 15_000);
+    }
+}
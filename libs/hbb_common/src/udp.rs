@@ -58,10 +58,8 @@ impl FramedSocket {
             .await?
             .next()
             .context("could not resolve to any address")?;
-        Ok(Self::Direct(UdpFramed::new(
-            UdpSocket::from_std(new_socket(addr, reuse, buf_size)?.into_udp_socket())?,
-            BytesCodec::new(),
-        )))
+        let socket = UdpSocket::from_std(new_socket(addr, reuse, buf_size)?.into_udp_socket())?;
+        Ok(Self::Direct(UdpFramed::new(socket, BytesCodec::new())))
     }
 
     pub async fn new_proxy<'a, 't, P: ToProxyAddrs, T: ToSocketAddrs>(
@@ -167,4 +165,19 @@ impl FramedSocket {
         }
         None
     }
+
+    /// Sets IP_TOS of a direct socket, a socket through a proxy is left as it is.
+    pub fn set_tos(&self, tos: Option<u32>) -> bool {
+        match self {
+            FramedSocket::Direct(x) => {
+                crate::socket_client::apply_tos(tos, |tos| x.get_ref().set_tos(tos))
+            }
+            FramedSocket::ProxySocks(_) => {
+                if tos.is_some() {
+                    log::debug!("IP_TOS is not applied through a socks5 proxy");
+                }
+                false
+            }
+        }
+    }
 }
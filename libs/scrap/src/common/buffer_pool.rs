@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+pub const DEFAULT_POOL_MAX_BYTES: usize = 64 << 20;
+// Buffers kept per size bucket, more are freed.
+const MAX_BUFFERS_PER_BUCKET: usize = 4;
+
+lazy_static::lazy_static! {
+    static ref POOL: Mutex<BufferPool> = Mutex::new(BufferPool::new(DEFAULT_POOL_MAX_BYTES));
+}
+
+// Frame buffers allocated because the pool had none to hand out.
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Frame buffers returned by the capturers, bucketed by the power of two below their capacity
+/// and bounded in total size. Only the letterboxed and blanked copies of the Wayland capturer
+/// are pooled, not the cropped and converted frames of the PipeWire recorder.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    max_bytes: usize,
+    bytes: usize,
+    buckets: HashMap<u32, Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// An empty buffer with at least `capacity`, `None` if none is pooled.
+    pub fn take(&mut self, capacity: usize) -> Option<Vec<u8>> {
+        let bucket = bucket_of(capacity.max(1));
+        for bucket in [bucket, bucket + 1] {
+            let Some(bufs) = self.buckets.get_mut(&bucket) else {
+                continue;
+            };
+            if let Some(i) = bufs.iter().position(|b| b.capacity() >= capacity) {
+                let buf = bufs.swap_remove(i);
+                self.bytes -= buf.capacity();
+                return Some(buf);
+            }
+        }
+        None
+    }
+
+    /// Keeps `buf` for the next `take` unless the pool or its bucket is full.
+    pub fn put(&mut self, mut buf: Vec<u8>) {
+        let capacity = buf.capacity();
+        if capacity == 0 || self.bytes + capacity > self.max_bytes {
+            return;
+        }
+        let bufs = self.buckets.entry(bucket_of(capacity)).or_default();
+        if bufs.len() < MAX_BUFFERS_PER_BUCKET {
+            buf.clear();
+            bufs.push(buf);
+            self.bytes += capacity;
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        if self.bytes > max_bytes {
+            self.buckets.clear();
+            self.bytes = 0;
+        }
+    }
+}
+
+fn bucket_of(capacity: usize) -> u32 {
+    usize::BITS - 1 - capacity.leading_zeros()
+}
+
+/// Bounds the shared pool, 0 to allocate every buffer and free it after use.
+pub fn set_pool_max_bytes(max_bytes: usize) {
+    POOL.lock().unwrap().set_max_bytes(max_bytes);
+}
+
+/// The bytes held by the shared pool and the buffers allocated because it had none.
+pub fn pool_stats() -> (usize, u64) {
+    (
+        POOL.lock().unwrap().bytes(),
+        ALLOCATIONS.load(Ordering::Relaxed),
+    )
+}
+
+/// A frame buffer from the shared pool, it goes back to the pool when dropped.
+#[derive(Debug, Default)]
+pub struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+    /// An empty buffer with at least `capacity`, from the pool if it has one.
+    pub fn take(capacity: usize) -> Self {
+        if let Some(buf) = POOL.lock().unwrap().take(capacity) {
+            return Self(buf);
+        }
+        if capacity > 0 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        Self(Vec::with_capacity(capacity))
+    }
+
+    /// Swaps in a buffer with at least `capacity` if this one is smaller, this one goes back to
+    /// the pool.
+    pub fn fit(&mut self, capacity: usize) {
+        if self.0.capacity() < capacity {
+            *self = Self::take(capacity);
+        }
+    }
+
+    /// Gives the buffer back to the pool, the next `fit` takes one from it again.
+    pub fn release(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        POOL.lock().unwrap().put(std::mem::take(&mut self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut pool = BufferPool::new(1 << 20);
+        assert!(pool.take(1000).is_none());
+        pool.put(Vec::with_capacity(1000));
+        assert_eq!(pool.bytes(), 1000);
+        // too large for the pooled one
+        assert!(pool.take(2000).is_none());
+        let buf = pool.take(900).unwrap();
+        assert!(buf.capacity() >= 1000 && buf.is_empty());
+        assert_eq!(pool.bytes(), 0);
+        // over the bound, freed
+        pool.put(Vec::with_capacity(2 << 20));
+        assert_eq!(pool.bytes(), 0);
+        for _ in 0..MAX_BUFFERS_PER_BUCKET + 1 {
+            pool.put(Vec::with_capacity(100));
+        }
+        assert_eq!(pool.bytes(), MAX_BUFFERS_PER_BUCKET * 100);
+        pool.set_max_bytes(0);
+        assert_eq!(pool.bytes(), 0);
+        pool.put(Vec::with_capacity(100));
+        assert!(pool.take(100).is_none());
+    }
+
+    #[test]
+    fn test_pool_sustained_capture() {
+        // three capturers of different sizes borrowing a buffer for each frame
+        let sizes = [1920 * 1080 * 4, 1280 * 720 * 4, 640 * 480 * 4];
+        let rounds = 100;
+        let mut capturers: Vec<PooledBuffer> = sizes.iter().map(|_| Default::default()).collect();
+        let (_, allocations) = pool_stats();
+        for _ in 0..rounds {
+            for (buf, &size) in capturers.iter_mut().zip(sizes.iter()) {
+                // the buffer of the previous frame goes back first, as `Capturer::frame` does
+                buf.release();
+                buf.fit(size);
+                assert!(buf.is_empty());
+                buf.resize(size, 0);
+            }
+        }
+        // one per capturer instead of one per frame
+        assert_eq!(pool_stats().1 - allocations, sizes.len() as u64);
+        // the buffers of the last frame are held until the next one, then they go back
+        let held: usize = capturers.iter().map(|buf| buf.capacity()).sum();
+        assert!(held >= sizes.iter().sum::<usize>());
+        let (pooled, _) = pool_stats();
+        capturers.iter_mut().for_each(PooledBuffer::release);
+        assert_eq!(pool_stats().0, pooled + held);
+    }
+}
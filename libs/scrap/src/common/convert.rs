@@ -25,6 +25,36 @@ pub fn convert_to_yuv(
     let src_pixfmt = captured.pixfmt();
     let src_width = captured.width();
     let src_height = captured.height();
+    // 10 bits frames are reduced to 8 bits BGRA, only SDR ones are passed through, so this is the
+    // same as their tone mapping. HDR ones are tone mapped by the capturer.
+    let mut argb = Vec::new();
+    let (src, src_stride, src_pixfmt) = match src_pixfmt {
+        crate::Pixfmt::AR30 | crate::Pixfmt::AB30 => {
+            if src_stride[0] < src_width * 4 || src.len() < src_stride[0] * src_height {
+                bail!(
+                    "wrong 10 bits src, stride {:?}, len {}",
+                    src_stride,
+                    src.len()
+                );
+            }
+            argb.resize(src_width * 4 * src_height, 0);
+            let f = if src_pixfmt == crate::Pixfmt::AR30 {
+                AR30ToARGB
+            } else {
+                AB30ToARGB
+            };
+            call_yuv!(f(
+                src.as_ptr(),
+                src_stride[0] as _,
+                argb.as_mut_ptr(),
+                (src_width * 4) as _,
+                src_width as _,
+                src_height as _,
+            ));
+            (&argb[..], vec![src_width * 4], crate::Pixfmt::BGRA)
+        }
+        _ => (src, src_stride, src_pixfmt),
+    };
     if src_width > dst_fmt.w || src_height > dst_fmt.h {
         bail!(
             "src rect > dst rect: ({src_width}, {src_height}) > ({},{})",
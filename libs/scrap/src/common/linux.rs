@@ -114,4 +114,20 @@ impl Display {
             Display::WAYLAND(d) => d.name(),
         }
     }
+
+    // `None` on X11, there is no PipeWire stream.
+    pub fn node_id(&self) -> Option<u32> {
+        match self {
+            Display::X11(_) => None,
+            Display::WAYLAND(d) => d.node_id(),
+        }
+    }
+
+    // Only a window shared through the portal has one.
+    pub fn window_token(&self) -> Option<u64> {
+        match self {
+            Display::X11(_) => None,
+            Display::WAYLAND(d) => d.window_token(),
+        }
+    }
 }
@@ -49,6 +49,7 @@ pub const STRIDE_ALIGN: usize = 64; // commonly used in libvpx vpx_img_alloc cal
 pub const HW_STRIDE_ALIGN: usize = 0; // recommended by av_frame_get_buffer
 
 pub mod aom;
+pub mod buffer_pool;
 pub mod record;
 mod vpx;
 
@@ -211,6 +212,9 @@ pub enum Pixfmt {
     I420,
     NV12,
     I444,
+    // 10 bits per color SDR, only captured on Wayland with `wayland-hdr-passthrough`
+    AR30,
+    AB30,
 }
 
 #[derive(Debug, Clone)]
@@ -235,7 +239,14 @@ pub fn is_cursor_embedded() -> bool {
     if is_x11() {
         x11::IS_CURSOR_EMBEDDED
     } else {
-        false
+        #[cfg(feature = "wayland")]
+        {
+            crate::wayland::pipewire::is_cursor_embedded()
+        }
+        #[cfg(not(feature = "wayland"))]
+        {
+            false
+        }
     }
 }
 
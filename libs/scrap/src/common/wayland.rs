@@ -4,9 +4,22 @@ use crate::{
 };
 use std::{io, sync::RwLock, time::Duration};
 
-use super::x11::PixelBuffer;
-
-pub struct Capturer(Display, Box<dyn Recorder>, Vec<u8>);
+use super::{buffer_pool::PooledBuffer, x11::PixelBuffer};
+
+// The letterboxed and blanked copies of a frame are taken from the shared pool for each frame
+// and go back to it when the next one is captured or the capturer is dropped, an idle capturer
+// keeps the ones of its last frame. The buffers of the recorder are not pooled.
+pub struct Capturer(
+    Display,
+    Box<dyn Recorder>,
+    PooledBuffer,
+    Option<(usize, usize)>,
+    // blanked regions in the native size, and the copy of the frame they are blanked in
+    Vec<DamageRect>,
+    PooledBuffer,
+    // set if the damage is attached to the frames
+    Option<DamageCell>,
+);
 
 
 lazy_static::lazy_static! {
@@ -28,36 +41,125 @@ fn map_err<E: ToString>(err: E) -> io::Error {
 impl Capturer {
     pub fn new(display: Display) -> io::Result<Capturer> {
         let r = display.0.recorder(false).map_err(map_err)?;
-        Ok(Capturer(display, r, Default::default()))
+        let damage = pipewire::is_damage_tracking()
+            .then(|| r.damage_cell())
+            .flatten();
+        Ok(Capturer(
+            display,
+            r,
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+            damage,
+        ))
+    }
+
+    /// Fill `rects` of every frame with black before it is scaled, e.g. to hide a password
+    /// manager while the full display is shared. They are relative to the display.
+    pub fn set_blank_rects(&mut self, rects: Vec<DamageRect>) {
+        self.4 = rects;
+    }
+
+    /// Scale and pad every frame to `target`, `None` for the native size.
+    pub fn set_target_resolution(&mut self, target: Option<(usize, usize)>) {
+        self.3 = target;
     }
 
     pub fn width(&self) -> usize {
-        self.0.width()
+        self.3.map(|t| t.0).unwrap_or_else(|| self.0.width())
     }
 
     pub fn height(&self) -> usize {
-        self.0.height()
+        self.3.map(|t| t.1).unwrap_or_else(|| self.0.height())
+    }
+
+    /// Dirty regions of the last frame, the full frame if the compositor does not report damage.
+    pub fn dirty_rects(&self) -> Vec<DamageRect> {
+        let full = vec![DamageRect::full(self.width(), self.height())];
+        if self.3.is_some() {
+            // damage is reported in the native size
+            return full;
+        }
+        self.1.damage().unwrap_or(full)
+    }
+}
+
+// A copy of `data` with `blanks` filled, `data` itself without any.
+fn blank<'a>(
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    blanks: &[DamageRect],
+    blanked: &'a mut PooledBuffer,
+) -> &'a [u8] {
+    if blanks.is_empty() {
+        return data;
+    }
+    blanked.fit(data.len());
+    blanked.clear();
+    blanked.extend_from_slice(data);
+    blank_rects(blanked, width, height, blanks);
+    blanked
+}
+
+// The damage of a `width`x`height` frame sent as `target`, the full frame if it is scaled.
+fn frame_damage(
+    cell: &DamageCell,
+    width: usize,
+    height: usize,
+    target: Option<(usize, usize)>,
+) -> Vec<DamageRect> {
+    let (w, h) = target.unwrap_or((width, height));
+    let damage = match cell.lock().unwrap().clone() {
+        Some(damage) if (w, h) == (width, height) => damage,
+        _ => vec![DamageRect::full(w, h)],
+    };
+    pipewire::add_damage_bytes_saved(unchanged_bytes(w, h, &damage));
+    damage
+}
+
+fn pixel_buffer<'a>(
+    data: &'a [u8],
+    pixfmt: crate::Pixfmt,
+    width: usize,
+    height: usize,
+    target: Option<(usize, usize)>,
+    buf: &'a mut PooledBuffer,
+) -> PixelBuffer<'a> {
+    match target {
+        Some((w, h)) if (w, h) != (width, height) => {
+            buf.fit(w * h * 4);
+            Letterbox::new(width, height, w, h).apply(data, buf);
+            PixelBuffer::new(buf, pixfmt, w, h)
+        }
+        _ => PixelBuffer::new(data, pixfmt, width, height),
     }
 }
 
 impl TraitCapturer for Capturer {
     fn frame<'a>(&'a mut self, timeout: Duration) -> io::Result<Frame<'a>> {
-        match self.1.capture(timeout.as_millis() as _).map_err(map_err)? {
-            PixelProvider::BGR0(w, h, x) => Ok(Frame::PixelBuffer(PixelBuffer::new(
-                x,
-                crate::Pixfmt::BGRA,
-                w,
-                h,
-            ))),
-            PixelProvider::RGB0(w, h, x) => Ok(Frame::PixelBuffer(PixelBuffer::new(
-                x,
-                crate::Pixfmt::RGBA,
-                w,
-                h,
-            ))),
-            PixelProvider::NONE => Err(std::io::ErrorKind::WouldBlock.into()),
-            _ => Err(map_err("Invalid data")),
-        }
+        let Capturer(_, recorder, buf, target, blanks, blanked, damage) = self;
+        buf.release();
+        blanked.release();
+        let (pixfmt, w, h, x) = match recorder
+            .capture(timeout.as_millis() as _)
+            .map_err(map_err)?
+        {
+            PixelProvider::BGR0(w, h, x) => (crate::Pixfmt::BGRA, w, h, x),
+            PixelProvider::RGB0(w, h, x) => (crate::Pixfmt::RGBA, w, h, x),
+            PixelProvider::AR30(w, h, x) => (crate::Pixfmt::AR30, w, h, x),
+            PixelProvider::AB30(w, h, x) => (crate::Pixfmt::AB30, w, h, x),
+            PixelProvider::NONE => return Err(std::io::ErrorKind::WouldBlock.into()),
+            _ => return Err(map_err("Invalid data")),
+        };
+        let damage = damage
+            .as_ref()
+            .map(|cell| frame_damage(cell, w, h, *target));
+        Ok(Frame::PixelBuffer(
+            pixel_buffer(blank(x, w, h, blanks, blanked), pixfmt, w, h, *target, buf)
+                .with_damage(damage),
+        ))
     }
 }
 
@@ -103,4 +205,23 @@ impl Display {
     pub fn name(&self) -> String {
         "".to_owned()
     }
+
+    /// HDR frames are tone mapped to SDR, known once the first frame is captured.
+    pub fn is_hdr(&self) -> bool {
+        pipewire::is_hdr(self.0.node_id())
+    }
+
+    // PipeWire node ids are 32 bits, the portal sends them as u32.
+    pub fn node_id(&self) -> Option<u32> {
+        u32::try_from(self.0.node_id()).ok()
+    }
+
+    // The PipeWire node id identifies the shared window.
+    pub fn window_token(&self) -> Option<u64> {
+        if self.0.is_window() {
+            Some(self.0.node_id())
+        } else {
+            None
+        }
+    }
 }
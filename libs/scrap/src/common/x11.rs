@@ -1,3 +1,5 @@
+#[cfg(feature = "wayland")]
+use crate::wayland::capturable::DamageRect;
 use crate::{common::TraitCapturer, x11, Frame, Pixfmt, TraitPixelBuffer};
 use std::{io, time::Duration};
 
@@ -38,6 +40,8 @@ pub struct PixelBuffer<'a> {
     width: usize,
     height: usize,
     stride: Vec<usize>,
+    #[cfg(feature = "wayland")]
+    damage: Option<Vec<DamageRect>>,
 }
 
 impl<'a> PixelBuffer<'a> {
@@ -51,10 +55,25 @@ impl<'a> PixelBuffer<'a> {
             width,
             height,
             stride,
+            #[cfg(feature = "wayland")]
+            damage: None,
         }
     }
 }
 
+#[cfg(feature = "wayland")]
+impl PixelBuffer<'_> {
+    pub fn with_damage(mut self, damage: Option<Vec<DamageRect>>) -> Self {
+        self.damage = damage;
+        self
+    }
+
+    /// The regions changed since the previous frame, `None` if the damage is not tracked.
+    pub fn damage(&self) -> Option<&[DamageRect]> {
+        self.damage.as_deref()
+    }
+}
+
 impl<'a> TraitPixelBuffer for PixelBuffer<'a> {
     fn data(&self) -> &[u8] {
         self.data
@@ -1,5 +1,6 @@
 pub mod capturable;
 pub mod pipewire;
+pub use pipewire::cursor_updates;
 mod screencast_portal;
 mod request_portal;
 pub mod remote_desktop_portal;
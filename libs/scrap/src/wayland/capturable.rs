@@ -1,5 +1,6 @@
 use std::boxed::Box;
 use std::error::Error;
+use std::sync::{Arc, Mutex};
 
 pub enum PixelProvider<'a> {
     // 8 bits per color
@@ -8,6 +9,9 @@ pub enum PixelProvider<'a> {
     BGR0(usize, usize, &'a [u8]),
     // width, height, stride
     BGR0S(usize, usize, usize, &'a [u8]),
+    // 10 bits per color and 2 bits alpha, blue or red in the low bits
+    AR30(usize, usize, &'a [u8]),
+    AB30(usize, usize, &'a [u8]),
     NONE,
 }
 
@@ -18,13 +22,248 @@ impl<'a> PixelProvider<'a> {
             PixelProvider::RGB0(w, h, _) => (*w, *h),
             PixelProvider::BGR0(w, h, _) => (*w, *h),
             PixelProvider::BGR0S(w, h, _, _) => (*w, *h),
+            PixelProvider::AR30(w, h, _) => (*w, *h),
+            PixelProvider::AB30(w, h, _) => (*w, *h),
             PixelProvider::NONE => (0, 0),
         }
     }
 }
 
+/// Cursor position relative to the frame, from the cursor metadata of a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorUpdate {
+    pub x: i32,
+    pub y: i32,
+    /// Only set if the image changed since the last update.
+    pub image: Option<CursorImage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorImage {
+    pub width: u32,
+    pub height: u32,
+    pub argb: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DamageRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl DamageRect {
+    pub fn full(width: usize, height: usize) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+}
+
+/// The damage of the last frame of a recorder, shared so it can be read while the frame is.
+pub type DamageCell = Arc<Mutex<Option<Vec<DamageRect>>>>;
+
+/// Fits a frame of `src` size into `dst`, keeping the aspect ratio and padding the rest with black.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Letterbox {
+    pub src: (usize, usize),
+    pub dst: (usize, usize),
+    pub scaled: (usize, usize),
+    pub offset: (usize, usize),
+}
+
+impl Letterbox {
+    pub fn new(src_w: usize, src_h: usize, dst_w: usize, dst_h: usize) -> Self {
+        let (src_w, src_h) = (src_w.max(1), src_h.max(1));
+        let scaled = if src_w * dst_h <= dst_w * src_h {
+            (src_w * dst_h / src_h, dst_h)
+        } else {
+            (dst_w, src_h * dst_w / src_w)
+        };
+        let scaled = (scaled.0.max(1), scaled.1.max(1));
+        Self {
+            src: (src_w, src_h),
+            dst: (dst_w, dst_h),
+            scaled,
+            offset: (
+                dst_w.saturating_sub(scaled.0) / 2,
+                dst_h.saturating_sub(scaled.1) / 2,
+            ),
+        }
+    }
+
+    /// Map a position in the target frame back to the source, positions in the padding are
+    /// clamped to the nearest edge.
+    pub fn to_source(&self, x: i32, y: i32) -> (i32, i32) {
+        let map = |v: i32, offset: usize, scaled: usize, src: usize| {
+            let v = (v as i64 - offset as i64).clamp(0, scaled as i64 - 1);
+            (v * src as i64 / scaled as i64) as i32
+        };
+        (
+            map(x, self.offset.0, self.scaled.0, self.src.0),
+            map(y, self.offset.1, self.scaled.1, self.src.1),
+        )
+    }
+
+    /// Map a position in the source into the target frame, the inverse of `to_source`.
+    pub fn to_target(&self, x: i32, y: i32) -> (i32, i32) {
+        let map = |v: i32, offset: usize, scaled: usize, src: usize| {
+            (v as i64 * scaled as i64 / src as i64 + offset as i64) as i32
+        };
+        (
+            map(x, self.offset.0, self.scaled.0, self.src.0),
+            map(y, self.offset.1, self.scaled.1, self.src.1),
+        )
+    }
+
+    /// Nearest neighbor scale of a 4 bytes per pixel frame into `dst`.
+    pub fn apply(&self, src: &[u8], dst: &mut Vec<u8>) {
+        let src_stride = src.len() / self.src.1;
+        let dst_stride = self.dst.0 * 4;
+        dst.clear();
+        dst.resize(dst_stride * self.dst.1, 0);
+        for dy in 0..self.scaled.1 {
+            let sy = dy * self.src.1 / self.scaled.1;
+            let src_row = &src[sy * src_stride..];
+            let dst_row = &mut dst[(dy + self.offset.1) * dst_stride + self.offset.0 * 4..];
+            for dx in 0..self.scaled.0 {
+                let sx = dx * self.src.0 / self.scaled.0;
+                dst_row[dx * 4..dx * 4 + 4].copy_from_slice(&src_row[sx * 4..sx * 4 + 4]);
+            }
+        }
+    }
+}
+
+// Brightness in nits mapped to white, and the brightness of SDR white (ITU-R BT.2408).
+const HDR_PEAK_NITS: f64 = 1000.;
+const SDR_WHITE_NITS: f64 = 203.;
+
+/// Whether the colorimetry of the caps is HDR, i.e. PQ (SMPTE ST 2084) encoded.
+pub fn is_pq_colorimetry(colorimetry: &str) -> bool {
+    let c = colorimetry.to_lowercase();
+    c.contains("2100-pq") || c.contains("smpte2084")
+}
+
+/// Converts 10 bits per channel frames, packed in 32 bits with 2 bits of alpha on top
+/// (`BGR10A2_LE`/`RGB10A2_LE`), to 8 bits BGRx.
+pub struct ToneMap {
+    lut: Vec<u8>,
+}
+
+impl ToneMap {
+    /// 10 bits SDR, only the precision is reduced.
+    pub fn sdr() -> Self {
+        Self {
+            lut: (0..1024u32).map(|v| (v >> 2) as u8).collect(),
+        }
+    }
+
+    /// PQ encoded HDR, tone mapped with extended Reinhard and sRGB encoded.
+    /// The BT.2020 primaries are kept as they are.
+    pub fn pq() -> Self {
+        const M1: f64 = 2610. / 16384.;
+        const M2: f64 = 2523. / 4096. * 128.;
+        const C1: f64 = 3424. / 4096.;
+        const C2: f64 = 2413. / 4096. * 32.;
+        const C3: f64 = 2392. / 4096. * 32.;
+        let white = HDR_PEAK_NITS / SDR_WHITE_NITS;
+        let lut = (0..1024u32)
+            .map(|v| {
+                let e = (v as f64 / 1023.).powf(1. / M2);
+                let nits = 10000. * ((e - C1).max(0.) / (C2 - C3 * e)).powf(1. / M1);
+                let x = nits / SDR_WHITE_NITS;
+                let y = (x * (1. + x / (white * white)) / (1. + x)).clamp(0., 1.);
+                (y.powf(1. / 2.2) * 255.).round() as u8
+            })
+            .collect();
+        Self { lut }
+    }
+
+    pub fn apply(&self, src: &[u8], rgb_first: bool, dst: &mut Vec<u8>) {
+        dst.clear();
+        dst.reserve(src.len());
+        for px in src.chunks_exact(4) {
+            let v = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+            let c0 = self.lut[(v & 0x3ff) as usize];
+            let c1 = self.lut[((v >> 10) & 0x3ff) as usize];
+            let c2 = self.lut[((v >> 20) & 0x3ff) as usize];
+            let (b, g, r) = if rgb_first {
+                (c2, c1, c0)
+            } else {
+                (c0, c1, c2)
+            };
+            dst.extend_from_slice(&[b, g, r, 255]);
+        }
+    }
+}
+
+/// Move the damage rects reported for the whole buffer into the (cropped) frame and clip them.
+/// Returns `None` if no damage is reported, the caller should treat the full frame as dirty.
+pub fn normalize_damage(
+    rects: &[DamageRect],
+    crop: Option<(usize, usize)>,
+    width: usize,
+    height: usize,
+) -> Option<Vec<DamageRect>> {
+    if rects.is_empty() {
+        return None;
+    }
+    let (x_off, y_off) = crop.unwrap_or((0, 0));
+    let rects = rects
+        .iter()
+        .filter_map(|r| {
+            let x0 = r.x.saturating_sub(x_off).min(width);
+            let y0 = r.y.saturating_sub(y_off).min(height);
+            let x1 = (r.x + r.width).saturating_sub(x_off).min(width);
+            let y1 = (r.y + r.height).saturating_sub(y_off).min(height);
+            if x1 > x0 && y1 > y0 {
+                Some(DamageRect {
+                    x: x0,
+                    y: y0,
+                    width: x1 - x0,
+                    height: y1 - y0,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+    Some(rects)
+}
+
+/// The bytes of a 4 bytes per pixel frame outside the damage, i.e. not sent by a partial update.
+/// Overlapping rects are counted twice, which understates it.
+pub fn unchanged_bytes(width: usize, height: usize, rects: &[DamageRect]) -> u64 {
+    let damaged: usize = rects.iter().map(|r| r.width * r.height).sum();
+    ((width * height).saturating_sub(damaged) * 4) as _
+}
+
+/// Fills `rects` of a 4 bytes per pixel frame with black, the parts outside the frame are ignored.
+pub fn blank_rects(data: &mut [u8], width: usize, height: usize, rects: &[DamageRect]) {
+    for r in normalize_damage(rects, None, width, height).unwrap_or_default() {
+        for y in r.y..r.y + r.height {
+            let start = (y * width + r.x) * 4;
+            if let Some(row) = data.get_mut(start..start + r.width * 4) {
+                row.fill(0);
+            }
+        }
+    }
+}
+
 pub trait Recorder {
     fn capture(&mut self, timeout_ms: u64) -> Result<PixelProvider, Box<dyn Error>>;
+    /// Damage of the last captured frame, `None` if the source does not provide it.
+    fn damage(&self) -> Option<Vec<DamageRect>> {
+        None
+    }
+    /// The damage of the last captured frame, shared, `None` if the source does not provide it.
+    fn damage_cell(&self) -> Option<DamageCell> {
+        None
+    }
 }
 
 pub trait BoxCloneCapturable {
@@ -58,3 +297,170 @@ impl Clone for Box<dyn Capturable> {
         self.box_clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_letterbox() {
+        // 4:3 into 16:9, pillarbox
+        let lb = Letterbox::new(1600, 1200, 1920, 1080);
+        assert_eq!(lb.scaled, (1440, 1080));
+        assert_eq!(lb.offset, (240, 0));
+        assert_eq!(lb.to_source(240, 0), (0, 0));
+        assert_eq!(lb.to_source(240 + 720, 540), (800, 600));
+        // in the padding
+        assert_eq!(lb.to_source(0, 0), (0, 0));
+        assert_eq!(lb.to_source(1919, 1079), (1598, 1198));
+        assert_eq!(lb.to_target(800, 600), (240 + 720, 540));
+        assert_eq!(lb.to_target(0, 0), (240, 0));
+        // 21:9 into 16:9, letterbox
+        let lb = Letterbox::new(3440, 1440, 1920, 1080);
+        assert_eq!(lb.scaled, (1920, 803));
+        assert_eq!(lb.offset, (0, 138));
+        // same size is identity
+        let lb = Letterbox::new(1920, 1080, 1920, 1080);
+        assert_eq!(lb.offset, (0, 0));
+        assert_eq!(lb.to_source(100, 200), (100, 200));
+
+        // 2x1 red/green into 2x2
+        let lb = Letterbox::new(2, 1, 2, 2);
+        let src = [0, 0, 255, 255, 0, 255, 0, 255];
+        let mut dst = Vec::new();
+        lb.apply(&src, &mut dst);
+        assert_eq!(dst.len(), 2 * 2 * 4);
+        assert_eq!(&dst[..8], &src);
+        assert!(dst[8..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_tone_map() {
+        assert!(is_pq_colorimetry("bt2100-pq"));
+        assert!(!is_pq_colorimetry("bt709"));
+        let pack = |c0: u32, c1: u32, c2: u32| (c0 | c1 << 10 | c2 << 20 | 3 << 30).to_le_bytes();
+        let mut src = vec![];
+        src.extend(pack(1023, 512, 0));
+        src.extend(pack(0, 4, 1023));
+        let mut dst = vec![];
+        let sdr = ToneMap::sdr();
+        sdr.apply(&src, false, &mut dst);
+        assert_eq!(dst, vec![255, 128, 0, 255, 0, 1, 255, 255]);
+        sdr.apply(&src, true, &mut dst);
+        assert_eq!(dst, vec![0, 128, 255, 255, 255, 1, 0, 255]);
+        let pq = ToneMap::pq();
+        assert_eq!(pq.lut[0], 0);
+        assert_eq!(pq.lut[1023], 255);
+        assert!(pq.lut.windows(2).all(|w| w[0] <= w[1]));
+        // SDR white is not blown out
+        let white = pq.lut[(0.58 * 1023.) as usize];
+        assert!(white > 128 && white < 250, "{}", white);
+    }
+
+    #[test]
+    fn test_blank_rects() {
+        let (width, height) = (4, 3);
+        let mut data = vec![0xffu8; width * height * 4];
+        let rects = [
+            DamageRect {
+                x: 1,
+                y: 0,
+                width: 2,
+                height: 1,
+            },
+            // clamped to the bottom right pixel
+            DamageRect {
+                x: 3,
+                y: 2,
+                width: 10,
+                height: 10,
+            },
+            // outside
+            DamageRect {
+                x: 4,
+                y: 0,
+                width: 1,
+                height: 1,
+            },
+        ];
+        blank_rects(&mut data, width, height, &rects);
+        let blanked: Vec<(usize, usize)> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|(x, y)| data[(y * width + x) * 4..][..4] == [0; 4])
+            .collect();
+        assert_eq!(blanked, vec![(1, 0), (2, 0), (3, 2)]);
+        assert!(data.iter().all(|x| *x == 0 || *x == 0xff));
+    }
+
+    #[test]
+    fn test_unchanged_bytes() {
+        assert_eq!(unchanged_bytes(4, 3, &[]), 48);
+        assert_eq!(unchanged_bytes(4, 3, &[DamageRect::full(4, 3)]), 0);
+        let rect = DamageRect {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+        };
+        assert_eq!(unchanged_bytes(4, 3, &[rect]), 32);
+        // never below zero
+        assert_eq!(unchanged_bytes(4, 3, &[DamageRect::full(4, 3), rect]), 0);
+    }
+
+    #[test]
+    fn test_normalize_damage() {
+        assert_eq!(normalize_damage(&[], None, 1920, 1080), None);
+        let rects = [
+            DamageRect {
+                x: 10,
+                y: 20,
+                width: 100,
+                height: 50,
+            },
+            DamageRect {
+                x: 1900,
+                y: 1000,
+                width: 100,
+                height: 100,
+            },
+        ];
+        assert_eq!(
+            normalize_damage(&rects, None, 1920, 1080),
+            Some(vec![
+                rects[0],
+                DamageRect {
+                    x: 1900,
+                    y: 1000,
+                    width: 20,
+                    height: 80,
+                }
+            ])
+        );
+        // cropped to (100, 0) 800x600, the first rect keeps its last 10 columns, the second one
+        // is out of the frame
+        assert_eq!(
+            normalize_damage(&rects, Some((100, 0)), 800, 600),
+            Some(vec![DamageRect {
+                x: 0,
+                y: 20,
+                width: 10,
+                height: 50,
+            }])
+        );
+        let rect = DamageRect {
+            x: 50,
+            y: 10,
+            width: 100,
+            height: 10,
+        };
+        assert_eq!(
+            normalize_damage(&[rect], Some((100, 0)), 800, 600),
+            Some(vec![DamageRect {
+                x: 0,
+                y: 10,
+                width: 50,
+                height: 10,
+            }])
+        );
+    }
+}
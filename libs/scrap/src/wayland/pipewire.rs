@@ -1,9 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::os::unix::io::AsRawFd;
 use std::process::Command;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
-use std::time::Duration;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
 use tracing::{debug, trace, warn};
 
 use dbus::{
@@ -20,7 +23,10 @@ use gstreamer_app::AppSink;
 use hbb_common::config;
 
 use super::capturable::PixelProvider;
-use super::capturable::{Capturable, Recorder};
+use super::capturable::{
+    is_pq_colorimetry, normalize_damage, Capturable, CursorImage, CursorUpdate, DamageCell,
+    DamageRect, Recorder, ToneMap,
+};
 use super::remote_desktop_portal::OrgFreedesktopPortalRemoteDesktop as remote_desktop_portal;
 use super::request_portal::OrgFreedesktopPortalRequestResponse;
 use super::screencast_portal::OrgFreedesktopPortalScreenCast as screencast_portal;
@@ -28,11 +34,295 @@ use lazy_static::lazy_static;
 
 lazy_static! {
     pub static ref RDP_RESPONSE: Mutex<Option<RdpResponse>> = Mutex::new(None);
+    // Whether the stream of a PipeWire node is HDR, set once a frame is captured.
+    static ref HDR_STREAMS: Mutex<HashMap<u64, bool>> = Default::default();
+    // Whether the source of a PipeWire node offers DMA-BUF buffers, set once its recorder runs.
+    static ref DMABUF_STREAMS: Mutex<HashMap<u64, bool>> = Default::default();
+    static ref TONE_MAP_SDR: ToneMap = ToneMap::sdr();
+    static ref TONE_MAP_PQ: ToneMap = ToneMap::pq();
+    static ref CURSOR_UPDATES: Mutex<VecDeque<CursorUpdate>> = Default::default();
+    // PipeWire nodes whose stream ended or failed, e.g. the monitor was unplugged.
+    static ref LOST_STREAMS: Mutex<HashSet<u64>> = Default::default();
+}
+
+// 10 bits per channel, offered by compositors for HDR outputs.
+const FORMAT_BGR10A2: &str = "BGR10A2_LE";
+const FORMAT_RGB10A2: &str = "RGB10A2_LE";
+
+static HDR_PASSTHROUGH: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HdrPath {
+    // 10 bits SDR frames are handed out as they are
+    Passthrough,
+    ToneMapPq,
+    // only the precision is reduced
+    ToneMapSdr,
+}
+
+/// Hand out 10 bits SDR frames as they are instead of reducing them to 8 bits.
+/// PQ encoded HDR frames are still tone mapped, the encoders only take 8 bits input for now.
+pub fn set_hdr_passthrough(v: bool) {
+    HDR_PASSTHROUGH.store(v, Ordering::SeqCst);
+}
+
+// Frames queued with the frame pacing, the oldest ones are dropped beyond it.
+const PACING_LOOKAHEAD: u32 = 3;
+
+static FRAME_PACING: AtomicBool = AtomicBool::new(false);
+// The capture interval of a paced recorder in microseconds, 0 if none is running.
+static PACING_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Queue a few frames and hand them out one per capture interval instead of as they arrive,
+/// which smooths the bursts after a stall. Applies to the recorders created afterwards.
+pub fn set_frame_pacing(v: bool) {
+    FRAME_PACING.store(v, Ordering::SeqCst);
+}
+
+static DAMAGE_TRACKING: AtomicBool = AtomicBool::new(false);
+static DAMAGE_BYTES_SAVED: AtomicU64 = AtomicU64::new(0);
+
+/// Attach the damage of the stream to every captured frame, the full frame if the stream does
+/// not carry any. pipewiresrc does not forward `SPA_META_VideoDamage`, only a source attaching
+/// region of interest metas of type `damage` gives partial rects.
+/// Applies to the capturers created afterwards.
+pub fn set_damage_tracking(v: bool) {
+    DAMAGE_TRACKING.store(v, Ordering::SeqCst);
+}
+
+pub fn is_damage_tracking() -> bool {
+    DAMAGE_TRACKING.load(Ordering::SeqCst)
+}
+
+/// The bytes of the frames captured with damage tracking outside their damage, the upper bound
+/// of what partial updates save.
+pub fn damage_bytes_saved() -> u64 {
+    DAMAGE_BYTES_SAVED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn add_damage_bytes_saved(n: u64) {
+    DAMAGE_BYTES_SAVED.fetch_add(n, Ordering::Relaxed);
+}
+
+/// The latency the frame pacing adds, one capture interval, `None` if no paced recorder runs.
+pub fn frame_pacing_latency() -> Option<Duration> {
+    match PACING_INTERVAL.load(Ordering::SeqCst) {
+        0 => None,
+        us => Some(Duration::from_micros(us)),
+    }
+}
+
+/// Schedules the frames one interval apart.
+#[derive(Debug, Default)]
+pub struct FramePacer {
+    next: Option<Instant>,
+}
+
+impl FramePacer {
+    /// How long the next frame is held back.
+    pub fn delay(&self, now: Instant) -> Duration {
+        self.next
+            .map_or(Duration::ZERO, |next| next.saturating_duration_since(now))
+    }
+
+    /// Schedules the next frame one interval after the due time of this one, or after `now`
+    /// if this one is late by an interval or more, so that a stall is not caught up in a burst.
+    pub fn release(&mut self, now: Instant, interval: Duration) {
+        let due = match self.next {
+            Some(next) if now.saturating_duration_since(next) < interval => next,
+            _ => now,
+        };
+        self.next = Some(due + interval);
+    }
+}
+
+fn is_10bit_format(pix_fmt: &str) -> bool {
+    pix_fmt == FORMAT_BGR10A2 || pix_fmt == FORMAT_RGB10A2
+}
+
+/// How a 10 bits frame is handed out, HDR is always tone mapped to SDR because the frames
+/// passed through are reduced to 8 bits without it before encoding.
+pub fn select_hdr_path(is_hdr: bool, passthrough: bool) -> HdrPath {
+    if is_hdr {
+        HdrPath::ToneMapPq
+    } else if passthrough {
+        HdrPath::Passthrough
+    } else {
+        HdrPath::ToneMapSdr
+    }
+}
+
+/// Whether the stream of the PipeWire node `path` is HDR, it is tone mapped to SDR then.
+/// `false` until the first frame is captured.
+pub fn is_hdr(path: u64) -> bool {
+    HDR_STREAMS
+        .lock()
+        .unwrap()
+        .get(&path)
+        .cloned()
+        .unwrap_or(false)
+}
+
+const CAPS_FEATURE_DMABUF: &str = "memory:DMABuf";
+
+fn caps_offer_dmabuf(caps: &gst::CapsRef) -> bool {
+    (0..caps.get_size()).any(|i| {
+        caps.get_features(i)
+            .map_or(false, |f| f.contains(CAPS_FEATURE_DMABUF))
+    })
+}
+
+/// Whether the source of the PipeWire node `path` offers DMA-BUF buffers, which could be encoded
+/// without copying them to system memory. `false` until its recorder is created.
+pub fn offers_dmabuf(path: u64) -> bool {
+    DMABUF_STREAMS
+        .lock()
+        .unwrap()
+        .get(&path)
+        .cloned()
+        .unwrap_or(false)
+}
+
+// https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html
+pub const SOURCE_TYPE_MONITOR: u32 = 1;
+pub const SOURCE_TYPE_WINDOW: u32 = 2;
+
+static CAPTURE_WINDOW: AtomicBool = AtomicBool::new(false);
+
+/// Ask the portal for a window instead of a monitor.
+/// The cached session is closed if the choice changes, the user is prompted again then.
+pub fn set_capture_window(v: bool) {
+    if CAPTURE_WINDOW.swap(v, Ordering::SeqCst) != v {
+        close_session();
+    }
+}
+
+/// A window is only requested if the portal offers window selection, a monitor otherwise.
+pub fn select_source_types(want_window: bool, available: Option<u32>) -> u32 {
+    match available {
+        Some(available) if want_window && available & SOURCE_TYPE_WINDOW != 0 => SOURCE_TYPE_WINDOW,
+        _ => SOURCE_TYPE_MONITOR,
+    }
+}
+
+pub const CURSOR_MODE_EMBEDDED: u32 = 2;
+pub const CURSOR_MODE_METADATA: u32 = 4;
+const MAX_CURSOR_UPDATES: usize = 64;
+
+static CURSOR_OVERLAY: AtomicBool = AtomicBool::new(false);
+// Set once a stream comes without the cursor metadata it was asked for, pipewiresrc does not
+// forward `SPA_META_Cursor`. The next sessions ask for the embedded cursor.
+static CURSOR_META_MISSING: AtomicBool = AtomicBool::new(false);
+// Frames of a stream in the metadata mode without the cursor before it counts as missing.
+const CURSOR_META_GRACE_FRAMES: u32 = 30;
+// The cursor mode requested from the portal, 0 if none.
+static CURSOR_MODE: AtomicU32 = AtomicU32::new(0);
+
+/// Ask the portal for the cursor as metadata instead of drawn into the frames, the cursor is
+/// drawn into them again if the stream does not carry it.
+/// The cached session is closed if the choice changes.
+pub fn set_cursor_overlay(v: bool) {
+    if CURSOR_OVERLAY.swap(v, Ordering::SeqCst) != v {
+        close_session();
+    }
+}
+
+/// The cursor is drawn into the frames if the portal does not offer cursor metadata.
+/// The portal default is kept without the overlay.
+pub fn select_cursor_mode(want_overlay: bool, available: Option<u32>) -> Option<u32> {
+    let available = available.filter(|_| want_overlay)?;
+    if available & CURSOR_MODE_METADATA != 0 {
+        Some(CURSOR_MODE_METADATA)
+    } else if available & CURSOR_MODE_EMBEDDED != 0 {
+        Some(CURSOR_MODE_EMBEDDED)
+    } else {
+        None
+    }
+}
+
+fn cursor_mode() -> Option<u32> {
+    Some(CURSOR_MODE.load(Ordering::SeqCst)).filter(|x| *x != 0)
+}
+
+pub fn is_cursor_embedded() -> bool {
+    cursor_mode() == Some(CURSOR_MODE_EMBEDDED)
+}
+
+/// Frames exclude the cursor, which is reported by `cursor_updates()`.
+pub fn is_cursor_metadata() -> bool {
+    cursor_mode() == Some(CURSOR_MODE_METADATA)
+}
+
+/// Cursor updates of the captured streams since the last call, oldest first.
+pub fn cursor_updates() -> Vec<CursorUpdate> {
+    CURSOR_UPDATES.lock().unwrap().drain(..).collect()
+}
+
+// Counts the frames without the cursor metadata until one has it, `None` once one has.
+// True if the stream is still without it after the grace frames.
+fn is_cursor_meta_missing(frames_without: &mut Option<u32>, present: bool) -> bool {
+    let Some(n) = frames_without else {
+        return false;
+    };
+    if present {
+        *frames_without = None;
+        return false;
+    }
+    *n += 1;
+    *n >= CURSOR_META_GRACE_FRAMES
+}
+
+// An update is only made if the position or the image changed.
+fn diff_cursor(
+    last: &mut Option<(i32, i32, CursorImage)>,
+    x: i32,
+    y: i32,
+    image: CursorImage,
+) -> Option<CursorUpdate> {
+    match last {
+        Some((lx, ly, limage)) if *limage == image => {
+            if (*lx, *ly) == (x, y) {
+                return None;
+            }
+            *lx = x;
+            *ly = y;
+            Some(CursorUpdate { x, y, image: None })
+        }
+        _ => {
+            *last = Some((x, y, image.clone()));
+            Some(CursorUpdate {
+                x,
+                y,
+                image: Some(image),
+            })
+        }
+    }
+}
+
+// The oldest update is dropped if nobody takes them, its image is kept for the next one.
+fn push_cursor_update(update: CursorUpdate) {
+    let mut queue = CURSOR_UPDATES.lock().unwrap();
+    if queue.len() >= MAX_CURSOR_UPDATES {
+        if let Some(dropped) = queue.pop_front() {
+            if let Some(next) = queue.front_mut() {
+                if next.image.is_none() {
+                    next.image = dropped.image;
+                }
+            }
+        }
+    }
+    queue.push_back(update);
 }
 
 #[inline]
 pub fn close_session() {
     let _ = RDP_RESPONSE.lock().unwrap().take();
+    LOST_STREAMS.lock().unwrap().clear();
+}
+
+/// The stream of the node ended, the portal session has to be opened again to capture it.
+pub fn is_stream_lost(node_id: u64) -> bool {
+    LOST_STREAMS.lock().unwrap().contains(&node_id)
 }
 
 #[inline]
@@ -125,6 +415,14 @@ impl PipeWireCapturable {
             size: res.unwrap_or(stream.size),
         }
     }
+
+    pub fn is_window(&self) -> bool {
+        self.source_type == SOURCE_TYPE_WINDOW as u64
+    }
+
+    pub fn node_id(&self) -> u64 {
+        self.path
+    }
 }
 
 impl std::fmt::Debug for PipeWireCapturable {
@@ -187,8 +485,12 @@ fn get_res(capturable: PipeWireCapturable) -> Result<(usize, usize), Box<dyn Err
 }
 
 pub struct PipeWireRecorder {
+    path: u64,
     buffer: Option<gst::MappedBuffer<gst::buffer::Readable>>,
     buffer_cropped: Vec<u8>,
+    // 10 bits frames converted to BGRx
+    buffer_converted: Vec<u8>,
+    is_hdr: Option<bool>,
     pix_fmt: String,
     is_cropped: bool,
     pipeline: gst::Pipeline,
@@ -196,10 +498,16 @@ pub struct PipeWireRecorder {
     width: usize,
     height: usize,
     saved_raw_data: Vec<u8>, // for faster compare and copy
+    damage: DamageCell,
+    no_damage_logged: bool,
+    last_cursor: Option<(i32, i32, CursorImage)>,
+    frames_without_cursor: Option<u32>,
+    pacer: Option<FramePacer>,
 }
 
 impl PipeWireRecorder {
     pub fn new(capturable: PipeWireCapturable) -> Result<Self, Box<dyn Error>> {
+        let path = capturable.path;
         let pipeline = gst::Pipeline::new(None);
 
         let src = gst::ElementFactory::make("pipewiresrc", None)?;
@@ -211,9 +519,17 @@ impl PipeWireRecorder {
         // see: https://gitlab.freedesktop.org/pipewire/pipewire/-/issues/982
         src.set_property("always-copy", &true)?;
 
+        let pacer = FRAME_PACING
+            .load(Ordering::SeqCst)
+            .then(FramePacer::default);
         let sink = gst::ElementFactory::make("appsink", None)?;
         sink.set_property("drop", &true)?;
-        sink.set_property("max-buffers", &1u32)?;
+        let max_buffers = if pacer.is_some() {
+            PACING_LOOKAHEAD
+        } else {
+            1u32
+        };
+        sink.set_property("max-buffers", &max_buffers)?;
 
         pipeline.add_many(&[&src, &sink])?;
         src.link(&sink)?;
@@ -230,29 +546,131 @@ impl PipeWireRecorder {
             "video/x-raw",
             &[("format", &"RGBx")],
         ));
+        // 8 bits formats are preferred, 10 bits ones are converted
+        caps.merge_structure(gst::structure::Structure::new(
+            "video/x-raw",
+            &[("format", &FORMAT_BGR10A2)],
+        ));
+        caps.merge_structure(gst::structure::Structure::new(
+            "video/x-raw",
+            &[("format", &FORMAT_RGB10A2)],
+        ));
         appsink.set_caps(Some(&caps));
 
         pipeline.set_state(gst::State::Playing)?;
+        // The appsink only accepts system memory, the caps of the source tell what it offers.
+        let dmabuf = src
+            .get_static_pad("src")
+            .and_then(|pad| pad.query_caps(None))
+            .map_or(false, |caps| caps_offer_dmabuf(&caps));
+        debug!("Stream {} offers DMA-BUF: {}", path, dmabuf);
+        DMABUF_STREAMS.lock().unwrap().insert(path, dmabuf);
         Ok(Self {
+            path,
             pipeline,
             appsink,
             buffer: None,
+            buffer_converted: vec![],
+            is_hdr: None,
             pix_fmt: "".into(),
             width: 0,
             height: 0,
             buffer_cropped: vec![],
             is_cropped: false,
             saved_raw_data: Vec::new(),
+            damage: Default::default(),
+            no_damage_logged: false,
+            last_cursor: None,
+            frames_without_cursor: Some(0),
+            pacer,
         })
     }
 }
 
+const DAMAGE_ROI_TYPE: &str = "damage";
+
+// The damage rects attached to `buf`, `None` if it has none.
+fn buffer_damage(buf: &gst::BufferRef) -> Option<Vec<DamageRect>> {
+    let damage: Vec<DamageRect> = buf
+        .iter_meta::<gstreamer_video::VideoRegionOfInterestMeta>()
+        .filter(|m| m.get_roi_type() == DAMAGE_ROI_TYPE)
+        .map(|m| {
+            let (x, y, w, h) = m.get_rect();
+            DamageRect {
+                x: x as _,
+                y: y as _,
+                width: w as _,
+                height: h as _,
+            }
+        })
+        .collect();
+    if damage.is_empty() {
+        None
+    } else {
+        Some(damage)
+    }
+}
+
+impl PipeWireRecorder {
+    // The cursor metadata is attached as the first rectangle of an overlay composition.
+    // Returns whether the buffer has it.
+    fn take_cursor(&mut self, buf: &gst::Buffer, offset: Option<(i32, i32)>) -> bool {
+        let Some(meta) = buf.get_meta::<gstreamer_video::VideoOverlayCompositionMeta>() else {
+            return false;
+        };
+        let Ok(rect) = meta.get_overlay().get_rectangle(0) else {
+            return false;
+        };
+        let (x, y, width, height) = rect.get_render_rectangle();
+        let pixels = rect.get_pixels_unscaled_argb(gstreamer_video::VideoOverlayFormatFlags::NONE);
+        let Ok(pixels) = pixels.map_readable() else {
+            return true;
+        };
+        let (x_off, y_off) = offset.unwrap_or_default();
+        let image = CursorImage {
+            width,
+            height,
+            argb: pixels.as_slice().to_vec(),
+        };
+        if let Some(update) = diff_cursor(&mut self.last_cursor, x - x_off, y - y_off, image) {
+            push_cursor_update(update);
+        }
+        true
+    }
+
+    // PipeWire removes the node of an unplugged monitor, its stream ends or fails then.
+    fn check_stream_lost(&self) -> bool {
+        let Some(msg) = self
+            .pipeline
+            .get_bus()
+            .and_then(|bus| bus.pop_filtered(&[gst::MessageType::Eos, gst::MessageType::Error]))
+        else {
+            return false;
+        };
+        warn!("Stream {} is lost: {:?}", self.path, msg);
+        LOST_STREAMS.lock().unwrap().insert(self.path);
+        true
+    }
+}
+
 impl Recorder for PipeWireRecorder {
     fn capture(&mut self, timeout_ms: u64) -> Result<PixelProvider, Box<dyn Error>> {
+        // the timeout is the capture interval
+        let interval = Duration::from_millis(timeout_ms);
+        if let Some(pacer) = self.pacer.as_ref() {
+            PACING_INTERVAL.store(interval.as_micros() as _, Ordering::SeqCst);
+            let delay = pacer.delay(Instant::now()).min(interval);
+            if !delay.is_zero() {
+                std::thread::sleep(delay);
+            }
+        }
         if let Some(sample) = self
             .appsink
             .try_pull_sample(gst::ClockTime::from_mseconds(timeout_ms))
         {
+            if let Some(pacer) = self.pacer.as_mut() {
+                pacer.release(Instant::now(), interval);
+            }
             let cap = sample
                 .get_caps()
                 .ok_or("Failed get caps")?
@@ -266,6 +684,20 @@ impl Recorder for PipeWireRecorder {
                 .get::<&str>("format")?
                 .ok_or("Failed to get pixel format")?
                 .to_string();
+            let is_hdr = is_10bit_format(&self.pix_fmt)
+                && cap
+                    .get::<&str>("colorimetry")
+                    .ok()
+                    .flatten()
+                    .map_or(false, is_pq_colorimetry);
+            if self.is_hdr != Some(is_hdr) {
+                debug!(
+                    "Stream {} is hdr: {}, format: {}",
+                    self.path, is_hdr, self.pix_fmt
+                );
+                self.is_hdr = Some(is_hdr);
+                HDR_STREAMS.lock().unwrap().insert(self.path, is_hdr);
+            }
 
             let buf = sample
                 .get_buffer_owned()
@@ -277,6 +709,31 @@ impl Recorder for PipeWireRecorder {
             if Some((0, 0, w as u32, h as u32)) == crop {
                 crop = None;
             }
+            let damage = buffer_damage(&buf);
+            if damage.is_none() && !self.no_damage_logged && is_damage_tracking() {
+                debug!(
+                    "Stream {} has no damage metadata, frames are full",
+                    self.path
+                );
+                self.no_damage_logged = true;
+            }
+            // The cursor moves without the frame changing.
+            if is_cursor_metadata() {
+                let present = self.take_cursor(&buf, crop.map(|(x, y, _, _)| (x as i32, y as i32)));
+                if is_cursor_meta_missing(&mut self.frames_without_cursor, present) {
+                    warn!(
+                        "Stream {} has no cursor metadata, ask for the embedded cursor",
+                        self.path
+                    );
+                    CURSOR_META_MISSING.store(true, Ordering::SeqCst);
+                    // the capturer is recreated as for a lost stream
+                    LOST_STREAMS.lock().unwrap().insert(self.path);
+                    return Err(Box::new(GStreamerError(format!(
+                        "Stream {} has no cursor metadata",
+                        self.path
+                    ))));
+                }
+            }
             let buf = buf
                 .into_mapped_buffer_readable()
                 .map_err(|_| GStreamerError("Failed to map buffer.".into()))?;
@@ -318,10 +775,22 @@ impl Recorder for PipeWireRecorder {
                     self.width = w;
                     self.height = h;
                 }
+                *self.damage.lock().unwrap() = normalize_damage(
+                    &damage.unwrap_or_default(),
+                    crop.map(|(x, y, _, _)| (x as _, y as _)),
+                    self.width,
+                    self.height,
+                );
                 self.is_cropped = crop.is_some();
                 self.buffer = Some(buf);
             }
         } else {
+            if self.check_stream_lost() {
+                return Err(Box::new(GStreamerError(format!(
+                    "Stream {} is lost",
+                    self.path
+                ))));
+            }
             return Ok(PixelProvider::NONE);
         }
         if self.buffer.is_none() {
@@ -338,16 +807,51 @@ impl Recorder for PipeWireRecorder {
         match self.pix_fmt.as_str() {
             "BGRx" => Ok(PixelProvider::BGR0(self.width, self.height, buf)),
             "RGBx" => Ok(PixelProvider::RGB0(self.width, self.height, buf)),
+            FORMAT_BGR10A2 | FORMAT_RGB10A2 => {
+                let rgb_first = self.pix_fmt == FORMAT_RGB10A2;
+                let tone_map = match select_hdr_path(
+                    self.is_hdr == Some(true),
+                    HDR_PASSTHROUGH.load(Ordering::SeqCst),
+                ) {
+                    HdrPath::Passthrough if rgb_first => {
+                        return Ok(PixelProvider::AB30(self.width, self.height, buf));
+                    }
+                    HdrPath::Passthrough => {
+                        return Ok(PixelProvider::AR30(self.width, self.height, buf));
+                    }
+                    HdrPath::ToneMapPq => &*TONE_MAP_PQ,
+                    HdrPath::ToneMapSdr => &*TONE_MAP_SDR,
+                };
+                tone_map.apply(buf, rgb_first, &mut self.buffer_converted);
+                Ok(PixelProvider::BGR0(
+                    self.width,
+                    self.height,
+                    &self.buffer_converted,
+                ))
+            }
             _ => Err(Box::new(GStreamerError(format!(
                 "Unreachable! Unknown pix_fmt, {}",
                 &self.pix_fmt
             )))),
         }
     }
+
+    fn damage(&self) -> Option<Vec<DamageRect>> {
+        self.damage.lock().unwrap().clone()
+    }
+
+    fn damage_cell(&self) -> Option<DamageCell> {
+        Some(self.damage.clone())
+    }
 }
 
 impl Drop for PipeWireRecorder {
     fn drop(&mut self) {
+        HDR_STREAMS.lock().unwrap().remove(&self.path);
+        DMABUF_STREAMS.lock().unwrap().remove(&self.path);
+        if self.pacer.is_some() {
+            PACING_INTERVAL.store(0, Ordering::SeqCst);
+        }
         if let Err(err) = self.pipeline.set_state(gst::State::Null) {
             warn!("Failed to stop GStreamer pipeline: {}.", err);
         }
@@ -537,6 +1041,21 @@ pub fn request_remote_desktop() -> Result<
             is_support_restore_token = true;
         }
     }
+    let source_types = select_source_types(
+        CAPTURE_WINDOW.load(Ordering::SeqCst),
+        screencast_portal::available_source_types(&portal).ok(),
+    );
+    debug!("Request screen cast source types {}", source_types);
+    let mut available_cursor_modes = screencast_portal::available_cursor_modes(&portal).ok();
+    if CURSOR_META_MISSING.load(Ordering::SeqCst) {
+        available_cursor_modes = available_cursor_modes.map(|x| x & !CURSOR_MODE_METADATA);
+    }
+    let cursor_mode = select_cursor_mode(
+        CURSOR_OVERLAY.load(Ordering::SeqCst),
+        available_cursor_modes,
+    );
+    debug!("Request screen cast cursor mode {:?}", cursor_mode);
+    CURSOR_MODE.store(cursor_mode.unwrap_or(0), Ordering::SeqCst);
 
     // The following code may be improved.
     // https://flatpak.github.io/xdg-desktop-portal/#:~:text=To%20avoid%20a%20race%20condition
@@ -559,6 +1078,7 @@ pub fn request_remote_desktop() -> Result<
             session.clone(),
             failure.clone(),
             is_support_restore_token,
+            source_types,
         ),
         failure_res.clone(),
     )?;
@@ -603,6 +1123,7 @@ fn on_create_session_response(
     session: Arc<Mutex<Option<dbus::Path<'static>>>>,
     failure: Arc<AtomicBool>,
     is_support_restore_token: bool,
+    source_types: u32,
 ) -> impl Fn(
     OrgFreedesktopPortalRequestResponse,
     &SyncConnection,
@@ -647,7 +1168,10 @@ fn on_create_session_response(
             );
             // https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html
             // args.insert("multiple".into(), Variant(Box::new(true)));
-            args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
+            args.insert("types".into(), Variant(Box::new(source_types)));
+            if let Some(mode) = cursor_mode() {
+                args.insert("cursor_mode".into(), Variant(Box::new(mode)));
+            }
 
             let path = portal.select_sources(ses.clone(), args)?;
             handle_response(
@@ -682,6 +1206,7 @@ fn on_create_session_response(
                     failure.clone(),
                     ses,
                     is_support_restore_token,
+                    source_types,
                 ),
                 failure.clone(),
             )?;
@@ -697,6 +1222,7 @@ fn on_select_devices_response(
     failure: Arc<AtomicBool>,
     session: dbus::Path<'static>,
     is_support_restore_token: bool,
+    source_types: u32,
 ) -> impl Fn(
     OrgFreedesktopPortalRequestResponse,
     &SyncConnection,
@@ -711,7 +1237,10 @@ fn on_select_devices_response(
         );
         // https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html
         // args.insert("multiple".into(), Variant(Box::new(true)));
-        args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
+        args.insert("types".into(), Variant(Box::new(source_types)));
+        if let Some(mode) = cursor_mode() {
+            args.insert("cursor_mode".into(), Variant(Box::new(mode)));
+        }
 
         let session = session.clone();
         let path = portal.select_sources(session.clone(), args)?;
@@ -873,3 +1402,147 @@ fn is_server_running() -> bool {
     let is_running = output_str.contains(&format!("{} --server", app_name));
     is_running
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_damage() {
+        gst::init().unwrap();
+        let mut buf = gst::Buffer::with_size(16).unwrap();
+        assert_eq!(buffer_damage(&buf), None);
+        {
+            let buf = buf.get_mut().unwrap();
+            gstreamer_video::VideoRegionOfInterestMeta::add(buf, "face", (0, 0, 5, 5));
+            assert_eq!(buffer_damage(buf), None);
+            gstreamer_video::VideoRegionOfInterestMeta::add(buf, DAMAGE_ROI_TYPE, (10, 20, 30, 40));
+        }
+        let damage = buffer_damage(&buf).unwrap();
+        // a damaged frame gives partial rects, moved into the crop
+        assert_eq!(
+            normalize_damage(&damage, Some((5, 10)), 100, 100),
+            Some(vec![DamageRect {
+                x: 5,
+                y: 10,
+                width: 30,
+                height: 40,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_frame_pacer() {
+        let interval = Duration::from_millis(10);
+        let start = Instant::now();
+        let mut pacer = FramePacer::default();
+        assert_eq!(pacer.delay(start), Duration::ZERO);
+        pacer.release(start, interval);
+        // a burst is spread one interval apart
+        assert_eq!(pacer.delay(start), interval);
+        pacer.release(start + interval, interval);
+        assert_eq!(pacer.delay(start + interval), interval);
+        // released a bit late, the schedule is kept
+        pacer.release(start + Duration::from_millis(23), interval);
+        assert_eq!(
+            pacer.delay(start + Duration::from_millis(23)),
+            Duration::from_millis(7)
+        );
+        // a stall is not caught up
+        let late = start + Duration::from_millis(100);
+        pacer.release(late, interval);
+        assert_eq!(pacer.delay(late), interval);
+    }
+
+    #[test]
+    fn test_select_source_types() {
+        let both = SOURCE_TYPE_MONITOR | SOURCE_TYPE_WINDOW;
+        assert_eq!(select_source_types(false, Some(both)), SOURCE_TYPE_MONITOR);
+        assert_eq!(select_source_types(true, Some(both)), SOURCE_TYPE_WINDOW);
+        // window selection not offered, or the portal is too old to tell
+        assert_eq!(
+            select_source_types(true, Some(SOURCE_TYPE_MONITOR)),
+            SOURCE_TYPE_MONITOR
+        );
+        assert_eq!(select_source_types(true, None), SOURCE_TYPE_MONITOR);
+    }
+
+    #[test]
+    fn test_hdr_path() {
+        assert!(is_10bit_format(FORMAT_BGR10A2));
+        assert!(is_10bit_format(FORMAT_RGB10A2));
+        assert!(!is_10bit_format("BGRx"));
+        assert_eq!(select_hdr_path(true, false), HdrPath::ToneMapPq);
+        assert_eq!(select_hdr_path(false, false), HdrPath::ToneMapSdr);
+        assert_eq!(select_hdr_path(true, true), HdrPath::ToneMapPq);
+        assert_eq!(select_hdr_path(false, true), HdrPath::Passthrough);
+    }
+
+    #[test]
+    fn test_select_cursor_mode() {
+        let all = 1 | CURSOR_MODE_EMBEDDED | CURSOR_MODE_METADATA;
+        assert_eq!(select_cursor_mode(false, Some(all)), None);
+        assert_eq!(
+            select_cursor_mode(true, Some(all)),
+            Some(CURSOR_MODE_METADATA)
+        );
+        // no cursor metadata, fall back to the embedded cursor
+        assert_eq!(
+            select_cursor_mode(true, Some(1 | CURSOR_MODE_EMBEDDED)),
+            Some(CURSOR_MODE_EMBEDDED)
+        );
+        assert_eq!(select_cursor_mode(true, Some(1)), None);
+        assert_eq!(select_cursor_mode(true, None), None);
+    }
+
+    #[test]
+    fn test_cursor_meta_missing() {
+        let mut frames_without = Some(0);
+        for _ in 1..CURSOR_META_GRACE_FRAMES {
+            assert!(!is_cursor_meta_missing(&mut frames_without, false));
+        }
+        assert!(is_cursor_meta_missing(&mut frames_without, false));
+
+        // once it comes, a later frame without it is fine
+        let mut frames_without = Some(0);
+        assert!(!is_cursor_meta_missing(&mut frames_without, true));
+        for _ in 0..CURSOR_META_GRACE_FRAMES {
+            assert!(!is_cursor_meta_missing(&mut frames_without, false));
+        }
+    }
+
+    #[test]
+    fn test_diff_cursor() {
+        let image = |v: u8| CursorImage {
+            width: 1,
+            height: 1,
+            argb: vec![v; 4],
+        };
+        let mut last = None;
+        assert_eq!(
+            diff_cursor(&mut last, 1, 2, image(0)),
+            Some(CursorUpdate {
+                x: 1,
+                y: 2,
+                image: Some(image(0))
+            })
+        );
+        assert_eq!(diff_cursor(&mut last, 1, 2, image(0)), None);
+        assert_eq!(
+            diff_cursor(&mut last, 3, 4, image(0)),
+            Some(CursorUpdate {
+                x: 3,
+                y: 4,
+                image: None
+            })
+        );
+        assert_eq!(
+            diff_cursor(&mut last, 3, 4, image(1)),
+            Some(CursorUpdate {
+                x: 3,
+                y: 4,
+                image: Some(image(1))
+            })
+        );
+    }
+}
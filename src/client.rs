@@ -44,7 +44,7 @@ use hbb_common::{
     rendezvous_proto::*,
     socket_client,
     sodiumoxide::{base64, crypto::sign},
-    tcp::FramedStream,
+    tcp::{ConnectOptions, FramedStream},
     timeout,
     tokio::time::Duration,
     AddrMangle, ResultType, Stream,
@@ -256,20 +256,27 @@ impl Client {
         }
         // to-do: remember the port for each peer, so that we can retry easier
         if hbb_common::is_ip_str(peer) {
-            return Ok((
-                socket_client::connect_tcp(check_port(peer, RELAY_PORT + 1), CONNECT_TIMEOUT)
-                    .await?,
-                true,
+            let mut conn = socket_client::connect_tcp_with_options(
+                check_port(peer, RELAY_PORT + 1),
                 None,
-            ));
+                CONNECT_TIMEOUT,
+                Self::direct_options(),
+            )
+            .await?;
+            crate::rendezvous_mediator::answer_direct_psk(&mut conn).await?;
+            return Ok((conn, true, None));
         }
         // Allow connect to {domain}:{port}
         if hbb_common::is_domain_port_str(peer) {
-            return Ok((
-                socket_client::connect_tcp(peer, CONNECT_TIMEOUT).await?,
-                true,
+            let mut conn = socket_client::connect_tcp_with_options(
+                peer,
                 None,
-            ));
+                CONNECT_TIMEOUT,
+                Self::direct_options(),
+            )
+            .await?;
+            crate::rendezvous_mediator::answer_direct_psk(&mut conn).await?;
+            return Ok((conn, true, None));
         }
 
         let other_server = interface.get_lch().read().unwrap().other_server.clone();
@@ -491,8 +498,13 @@ impl Client {
         log::info!("peer address: {}, timeout: {}", peer, connect_timeout);
         let start = std::time::Instant::now();
         // NOTICE: Socks5 is be used event in intranet. Which may be not a good way.
-        let mut conn =
-            socket_client::connect_tcp_local(peer, Some(local_addr), connect_timeout).await;
+        let mut conn = socket_client::connect_tcp_with_options(
+            peer,
+            Some(local_addr),
+            connect_timeout,
+            Self::direct_options(),
+        )
+        .await;
         let mut direct = !conn.is_err();
         interface.update_direct(Some(direct));
         if interface.is_force_relay() || conn.is_err() {
@@ -670,6 +682,14 @@ impl Client {
         Self::create_relay(peer, uuid, relay_server, key, conn_type, ipv4).await
     }
 
+    #[inline]
+    fn direct_options() -> ConnectOptions {
+        ConnectOptions {
+            tos: socket_client::get_tos(),
+            ..Default::default()
+        }
+    }
+
     /// Create a relay connection to the server.
     async fn create_relay(
         peer: &str,
@@ -679,9 +699,15 @@ impl Client {
         conn_type: ConnType,
         ipv4: bool,
     ) -> ResultType<Stream> {
-        let mut conn = socket_client::connect_tcp(
+        let mut conn = socket_client::connect_tcp_with_options(
             socket_client::ipv4_to_ipv6(check_port(relay_server, RELAY_PORT), ipv4),
+            None,
             CONNECT_TIMEOUT,
+            ConnectOptions {
+                buffers: socket_client::get_relay_buffers(),
+                tos: socket_client::get_tos(),
+                ..Default::default()
+            },
         )
         .await
         .with_context(|| "Failed to connect to relay server")?;
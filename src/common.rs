@@ -1,7 +1,10 @@
 use std::{
     collections::HashMap,
     future::Future,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
     task::Poll,
 };
 
@@ -12,7 +15,7 @@ use hbb_common::{
     anyhow::{anyhow, Context},
     bail, base64,
     bytes::Bytes,
-    config::{self, Config, CONNECT_TIMEOUT, READ_TIMEOUT, RENDEZVOUS_PORT},
+    config::{self, keys, Config, CONNECT_TIMEOUT, READ_TIMEOUT, RENDEZVOUS_PORT},
     futures::future::join_all,
     futures_util::future::poll_fn,
     get_version_number, log,
@@ -81,6 +84,7 @@ lazy_static::lazy_static! {
     // Is server logic running. The server code can invoked to run by the main process if --server is not running.
     static ref SERVER_RUNNING: Arc<RwLock<bool>> = Default::default();
     static ref IS_MAIN: bool = std::env::args().nth(1).map_or(true, |arg| !arg.starts_with("--"));
+    static ref NAT_TEST_SERVER: Mutex<String> = Default::default();
 }
 
 pub struct SimpleCallOnReturn {
@@ -463,6 +467,50 @@ audio_rechannel!(audio_rechannel_8_5, 8, 5);
 audio_rechannel!(audio_rechannel_8_6, 8, 6);
 audio_rechannel!(audio_rechannel_8_7, 8, 7);
 
+const DEFAULT_NAT_TEST_TIMEOUT: u64 = 10_000;
+// Results compared before the nat type is decided, unless the first one is symmetric already.
+const MAX_NAT_TEST_RESULTS: usize = 2;
+// Set if the last nat test timed out, the nat type is kept until a test completes.
+static NAT_TYPE_PROVISIONAL: AtomicBool = AtomicBool::new(false);
+
+/// Whether the nat type is not tested yet because the nat test timed out, it is retried in the
+/// background.
+pub fn is_nat_type_provisional() -> bool {
+    NAT_TYPE_PROVISIONAL.load(Ordering::SeqCst)
+}
+
+fn get_nat_test_timeout() -> u64 {
+    match Config::get_option(keys::OPTION_NAT_TEST_TIMEOUT).parse::<u64>() {
+        Ok(ms) if ms > 0 => ms,
+        _ => DEFAULT_NAT_TEST_TIMEOUT,
+    }
+}
+
+// A timed out test counts as a failed one, so it is retried with the same backoff.
+async fn with_nat_test_timeout<F: Future<Output = ResultType<bool>>>(
+    ms: u64,
+    fut: F,
+) -> ResultType<bool> {
+    match timeout(ms, fut).await {
+        Ok(res) => {
+            if let Ok(true) = res {
+                NAT_TYPE_PROVISIONAL.store(false, Ordering::SeqCst);
+            }
+            res
+        }
+        Err(_) => {
+            log::warn!(
+                "test nat: timeout after {}ms, keep nat type {} for now",
+                ms,
+                Config::get_nat_type()
+            );
+            NAT_TYPE_PROVISIONAL.store(true, Ordering::SeqCst);
+            Ok(false)
+        }
+    }
+}
+
+// It runs in its own thread, the rendezvous mediator does not wait for it.
 pub fn test_nat_type() {
     let mut i = 0;
     std::thread::spawn(move || loop {
@@ -486,6 +534,10 @@ pub fn test_nat_type() {
 
 #[tokio::main(flavor = "current_thread")]
 async fn test_nat_type_() -> ResultType<bool> {
+    with_nat_test_timeout(get_nat_test_timeout(), test_nat_type_async()).await
+}
+
+async fn test_nat_type_async() -> ResultType<bool> {
     log::info!("Testing nat ...");
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     let is_direct = crate::ipc::get_socks_async(1_000).await.is_none(); // sync socks BTW
@@ -496,9 +548,77 @@ async fn test_nat_type_() -> ResultType<bool> {
         return Ok(true);
     }
     let start = std::time::Instant::now();
-    let (rendezvous_server, _, _) = get_rendezvous_server(1_000).await;
-    let server1 = rendezvous_server;
-    let server2 = crate::increase_port(&server1, -1);
+    let (rendezvous_server, others, _) = get_rendezvous_server(1_000).await;
+    let servers = get_nat_test_servers(rendezvous_server, others);
+    let mut results = Vec::new();
+    let mut last_err = None;
+    for server in servers {
+        match probe_nat_type(&server).await {
+            Ok(Some(t)) => {
+                log::debug!("Nat type reported by {}: {:?}", server, t);
+                let restrictive = t == NatType::SYMMETRIC;
+                results.push((server, t));
+                // nothing more restrictive can be reported
+                if restrictive || results.len() >= MAX_NAT_TEST_RESULTS {
+                    break;
+                }
+            }
+            Ok(None) => log::warn!("test nat: no response from {}", server),
+            Err(err) => {
+                log::warn!("test nat: {} failed, {}", server, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    let Some((server, t)) = reconcile_nat_types(&results) else {
+        return match last_err {
+            Some(err) => Err(err),
+            None => Ok(false),
+        };
+    };
+    Config::set_nat_type(t as _);
+    *NAT_TEST_SERVER.lock().unwrap() = server.clone();
+    log::info!(
+        "Tested nat type: {:?} by {} in {:?}",
+        t,
+        server,
+        start.elapsed()
+    );
+    Ok(true)
+}
+
+// `nat-test-servers`, comma separated, or the rendezvous server followed by the other configured
+// ones. Each one is probed on its port and the port below it.
+fn get_nat_test_servers(rendezvous_server: String, others: Vec<String>) -> Vec<String> {
+    let servers: Vec<String> = Config::get_option(keys::OPTION_NAT_TEST_SERVERS)
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| socket_client::check_port(x, config::RENDEZVOUS_PORT))
+        .collect();
+    if !servers.is_empty() {
+        return servers;
+    }
+    std::iter::once(rendezvous_server).chain(others).collect()
+}
+
+// Conflicting results are settled on the more restrictive type, with the server reporting it.
+fn reconcile_nat_types(results: &[(String, NatType)]) -> Option<(String, NatType)> {
+    results
+        .iter()
+        .find(|(_, t)| *t == NatType::SYMMETRIC)
+        .or(results.first())
+        .cloned()
+}
+
+/// The server the current nat type was tested with, empty if not tested yet.
+pub fn nat_test_server() -> String {
+    NAT_TEST_SERVER.lock().unwrap().clone()
+}
+
+// `None` if `server1` or the port below it does not answer.
+async fn probe_nat_type(server1: &str) -> ResultType<Option<NatType>> {
+    let server2 = crate::increase_port(server1, -1);
     let mut msg_out = RendezvousMessage::new();
     let serial = Config::get_serial();
     msg_out.set_test_nat_request(TestNatRequest {
@@ -509,7 +629,7 @@ async fn test_nat_type_() -> ResultType<bool> {
     let mut port2 = 0;
     let mut local_addr = None;
     for i in 0..2 {
-        let server = if i == 0 { &*server1 } else { &*server2 };
+        let server = if i == 0 { server1 } else { &*server2 };
         let mut socket =
             socket_client::connect_tcp_local(server, local_addr, CONNECT_TIMEOUT).await?;
         if i == 0 {
@@ -541,17 +661,15 @@ async fn test_nat_type_() -> ResultType<bool> {
             break;
         }
     }
-    let ok = port1 > 0 && port2 > 0;
-    if ok {
-        let t = if port1 == port2 {
+    if port1 > 0 && port2 > 0 {
+        Ok(Some(if port1 == port2 {
             NatType::ASYMMETRIC
         } else {
             NatType::SYMMETRIC
-        };
-        Config::set_nat_type(t as _);
-        log::info!("Tested nat type: {:?} in {:?}", t, start.elapsed());
+        }))
+    } else {
+        Ok(None)
     }
-    Ok(ok)
 }
 
 pub async fn get_rendezvous_server(ms_timeout: u64) -> (String, Vec<String>, bool) {
@@ -616,25 +734,23 @@ async fn test_rendezvous_server_() {
     let mut futs = Vec::new();
     for host in servers {
         futs.push(tokio::spawn(async move {
-            let tm = std::time::Instant::now();
-            if socket_client::connect_tcp(
-                crate::check_port(&host, RENDEZVOUS_PORT),
-                CONNECT_TIMEOUT,
-            )
-            .await
-            .is_ok()
-            {
-                let elapsed = tm.elapsed().as_micros();
-                Config::update_latency(&host, elapsed as _);
-            } else {
-                Config::update_latency(&host, -1);
-            }
+            let latency = probe_rendezvous_server(&host).await.unwrap_or(-1);
+            Config::update_latency(&host, latency);
         }));
     }
     join_all(futs).await;
     Config::reset_online();
 }
 
+/// Time in microseconds to connect to the rendezvous server, `None` if it is not reachable.
+pub async fn probe_rendezvous_server(host: &str) -> Option<i64> {
+    let tm = std::time::Instant::now();
+    socket_client::connect_tcp(crate::check_port(host, RENDEZVOUS_PORT), CONNECT_TIMEOUT)
+        .await
+        .ok()?;
+    Some(tm.elapsed().as_micros() as _)
+}
+
 // #[cfg(any(target_os = "android", target_os = "ios", feature = "cli"))]
 pub fn test_rendezvous_server() {
     std::thread::spawn(test_rendezvous_server_);
@@ -1496,6 +1612,41 @@ mod tests {
             / 1000
     }
 
+    #[tokio::test]
+    async fn test_nat_test_timeout() {
+        let start = Instant::now();
+        let res = with_nat_test_timeout(100, async {
+            sleep(Duration::from_secs(10)).await;
+            Ok(true)
+        })
+        .await;
+        assert!(matches!(res, Ok(false)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(is_nat_type_provisional());
+        let res = with_nat_test_timeout(100, async { Ok(true) }).await;
+        assert!(matches!(res, Ok(true)));
+        assert!(!is_nat_type_provisional());
+    }
+
+    #[test]
+    fn test_reconcile_nat_types() {
+        assert_eq!(reconcile_nat_types(&[]), None);
+        let a = ("a:21116".to_owned(), NatType::ASYMMETRIC);
+        let b = ("b:21116".to_owned(), NatType::SYMMETRIC);
+        let c = ("c:21116".to_owned(), NatType::ASYMMETRIC);
+        assert_eq!(reconcile_nat_types(&[a.clone()]), Some(a.clone()));
+        assert_eq!(
+            reconcile_nat_types(&[a.clone(), c.clone()]),
+            Some(a.clone())
+        );
+        // the more restrictive type wins, whichever server reports it
+        assert_eq!(
+            reconcile_nat_types(&[a.clone(), b.clone()]),
+            Some(b.clone())
+        );
+        assert_eq!(reconcile_nat_types(&[b.clone(), c]), Some(b));
+    }
+
     fn interval_maker() -> Interval {
         interval(Duration::from_secs(1))
     }
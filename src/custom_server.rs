@@ -1,11 +1,15 @@
 use hbb_common::{
     bail,
     base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _},
+    config::{keys, Config, RS_PUB_KEY},
+    log,
     sodiumoxide::crypto::sign,
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
 
+const SERVER_CONFIG_VERSION_KEY: &str = "server-config-version";
+
 #[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
 pub struct CustomServer {
     #[serde(default)]
@@ -106,6 +110,169 @@ pub fn get_custom_server_from_string(s: &str) -> ResultType<CustomServer> {
     bail!("Failed to parse");
 }
 
+// Portable bundle of the rendezvous/relay settings, used for fleet provisioning.
+// `version` is a revision chosen by the administrator, older bundles are rejected.
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
+pub struct ServerConfigBundle {
+    #[serde(default)]
+    pub version: i64,
+    #[serde(default)]
+    pub rendezvous_server: String,
+    #[serde(default)]
+    pub relay_server: String,
+    #[serde(default)]
+    pub api_server: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub direct_server: String,
+    #[serde(default)]
+    pub direct_access_port: String,
+}
+
+impl ServerConfigBundle {
+    fn load() -> Self {
+        Self {
+            version: Config::get_option(SERVER_CONFIG_VERSION_KEY)
+                .parse()
+                .unwrap_or(0),
+            rendezvous_server: Config::get_option(keys::OPTION_CUSTOM_RENDEZVOUS_SERVER),
+            relay_server: Config::get_option(keys::OPTION_RELAY_SERVER),
+            api_server: Config::get_option(keys::OPTION_API_SERVER),
+            key: Config::get_option(keys::OPTION_KEY),
+            direct_server: Config::get_option(keys::OPTION_DIRECT_SERVER),
+            direct_access_port: Config::get_option(keys::OPTION_DIRECT_ACCESS_PORT),
+        }
+    }
+
+    fn validate(&self) -> ResultType<()> {
+        if self.version <= 0 {
+            bail!("Invalid server config version: {}", self.version);
+        }
+        for host in [&self.rendezvous_server, &self.relay_server] {
+            if !host.is_empty() && host.contains(char::is_whitespace) {
+                bail!("Invalid server address: {}", host);
+            }
+        }
+        if !self.key.is_empty() && crate::get_rs_pk(&self.key).is_none() {
+            bail!("Invalid key: {}", self.key);
+        }
+        if !self.direct_access_port.is_empty()
+            && self.direct_access_port.parse::<u16>().unwrap_or(0) == 0
+        {
+            bail!("Invalid direct access port: {}", self.direct_access_port);
+        }
+        Ok(())
+    }
+}
+
+/// The unsigned bundle of the current settings. Once a key is configured, `import_server_config`
+/// only takes it after the owner of the rendezvous server signs it with `sign_server_config`
+/// and the private key paired with that key, e.g. `id_ed25519` of hbbs.
+pub fn export_server_config() -> String {
+    let mut bundle = ServerConfigBundle::load();
+    if bundle.version <= 0 {
+        bundle.version = 1;
+    }
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(&bundle).unwrap_or_default())
+}
+
+/// Signs an exported bundle with `sk`, which must pair with the key configured on the devices
+/// importing it, not with a new key in the bundle.
+pub fn sign_server_config(s: &str, sk: &sign::SecretKey) -> ResultType<String> {
+    parse_server_config(s, None, 0)?;
+    let data = URL_SAFE_NO_PAD.decode(s.trim())?;
+    Ok(URL_SAFE_NO_PAD.encode(sign::sign(&data, sk)))
+}
+
+// The bundle is either plain json or signed by the private key paired with `pk`,
+// the signed form is required if `pk` is given.
+fn parse_server_config(
+    s: &str,
+    pk: Option<&sign::PublicKey>,
+    min_version: i64,
+) -> ResultType<ServerConfigBundle> {
+    let data = URL_SAFE_NO_PAD.decode(s.trim())?;
+    let bundle = if let Some(pk) = pk {
+        let Ok(data) = sign::verify(&data, pk) else {
+            bail!("sign:verify failed");
+        };
+        serde_json::from_slice::<ServerConfigBundle>(&data)?
+    } else {
+        serde_json::from_slice::<ServerConfigBundle>(&data)?
+    };
+    bundle.validate()?;
+    if bundle.version < min_version {
+        bail!(
+            "Server config version {} is older than the current one {}",
+            bundle.version,
+            min_version
+        );
+    }
+    Ok(bundle)
+}
+
+// Once a key is configured, the bundle must be signed by the private key paired with it, so
+// only the owner of the rendezvous server in use can provision a new one. Without a key it is
+// verified with the built-in one, an unsigned bundle is only taken if `allow_unsigned` is set,
+// i.e. with `allow-unsigned-server-config`.
+fn check_server_config(
+    s: &str,
+    key: &str,
+    allow_unsigned: bool,
+    min_version: i64,
+) -> ResultType<ServerConfigBundle> {
+    let data = URL_SAFE_NO_PAD.decode(s.trim())?;
+    if serde_json::from_slice::<ServerConfigBundle>(&data).is_ok() {
+        if !key.is_empty() {
+            bail!("Unsigned server config, it must be signed for the configured key");
+        }
+        if !allow_unsigned {
+            bail!("Unsigned server config is not allowed");
+        }
+        return parse_server_config(s, None, min_version);
+    }
+    let key = if key.is_empty() { RS_PUB_KEY } else { key };
+    let Some(pk) = crate::get_rs_pk(key) else {
+        bail!("Invalid key: {}", key);
+    };
+    parse_server_config(s, Some(&pk), min_version)
+}
+
+pub fn import_server_config(s: &str) -> ResultType<()> {
+    let current = ServerConfigBundle::load();
+    let allow_unsigned = Config::get_option(keys::OPTION_ALLOW_UNSIGNED_SERVER_CONFIG) == "Y";
+    let bundle = check_server_config(s, &current.key, allow_unsigned, current.version)?;
+    let mut options = Config::get_options();
+    for (k, v) in [
+        (
+            keys::OPTION_CUSTOM_RENDEZVOUS_SERVER,
+            &bundle.rendezvous_server,
+        ),
+        (keys::OPTION_RELAY_SERVER, &bundle.relay_server),
+        (keys::OPTION_API_SERVER, &bundle.api_server),
+        (keys::OPTION_KEY, &bundle.key),
+        (keys::OPTION_DIRECT_SERVER, &bundle.direct_server),
+        (keys::OPTION_DIRECT_ACCESS_PORT, &bundle.direct_access_port),
+    ] {
+        if v.is_empty() {
+            options.remove(k);
+        } else {
+            options.insert(k.to_owned(), v.to_owned());
+        }
+    }
+    options.insert(
+        SERVER_CONFIG_VERSION_KEY.to_owned(),
+        bundle.version.to_string(),
+    );
+    Config::set_options(options);
+    log::info!("Imported server config of version {}", bundle.version);
+    if current != bundle {
+        crate::RendezvousMediator::restart();
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -189,4 +356,109 @@ mod test {
             get_custom_server_from_string("rustdesk-licensed--0nI900VsFHZVBVdIlncwpHS4V0bOZ0dtVldrpVO4JHdCp0YV5WdzUGZzdnYRVjI6ISeltmIsISMuEjLx4SMiojI0N3boJye--.exe")
                 .unwrap(), lic);
     }
+
+    #[test]
+    fn test_server_config_bundle() {
+        let bundle = ServerConfigBundle {
+            version: 3,
+            rendezvous_server: "rs.example.net".to_owned(),
+            relay_server: "relay.example.net:21117".to_owned(),
+            key: "5Qbwsde3unUcJBtrx9ZkvUmwFNoExHzpryHuPUdqlWM=".to_owned(),
+            direct_server: "Y".to_owned(),
+            direct_access_port: "21118".to_owned(),
+            ..Default::default()
+        };
+        let json = serde_json::to_vec(&bundle).unwrap();
+        let plain = URL_SAFE_NO_PAD.encode(&json);
+        assert_eq!(parse_server_config(&plain, None, 0).unwrap(), bundle);
+        assert_eq!(parse_server_config(&plain, None, 3).unwrap(), bundle);
+        // downgrade
+        assert!(parse_server_config(&plain, None, 4).is_err());
+
+        let (pk, sk) = sign::gen_keypair();
+        let signed = sign::sign(&json, &sk);
+        let encoded = URL_SAFE_NO_PAD.encode(&signed);
+        assert_eq!(parse_server_config(&encoded, Some(&pk), 0).unwrap(), bundle);
+        // unsigned bundle is not accepted when a signature is required
+        assert!(parse_server_config(&plain, Some(&pk), 0).is_err());
+        // tampered
+        let mut tampered = signed.clone();
+        let n = tampered.len();
+        tampered[n - 2] ^= 0x01;
+        let tampered = URL_SAFE_NO_PAD.encode(&tampered);
+        assert!(parse_server_config(&tampered, Some(&pk), 0).is_err());
+        // signed by another key
+        let (pk2, _) = sign::gen_keypair();
+        assert!(parse_server_config(&encoded, Some(&pk2), 0).is_err());
+
+        let invalid = ServerConfigBundle {
+            version: 0,
+            ..bundle.clone()
+        };
+        let invalid = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&invalid).unwrap());
+        assert!(parse_server_config(&invalid, None, 0).is_err());
+    }
+
+    #[test]
+    fn test_check_server_config() {
+        let bundle = ServerConfigBundle {
+            version: 2,
+            rendezvous_server: "rs.example.net".to_owned(),
+            ..Default::default()
+        };
+        let json = serde_json::to_vec(&bundle).unwrap();
+        let plain = URL_SAFE_NO_PAD.encode(&json);
+        let (pk, sk) = sign::gen_keypair();
+        let key = crate::encode64(pk.0);
+        let signed = sign::sign(&json, &sk);
+        let encoded = URL_SAFE_NO_PAD.encode(&signed);
+
+        // unsigned
+        assert!(check_server_config(&plain, &key, true, 0).is_err());
+        assert!(check_server_config(&plain, "", false, 0).is_err());
+        assert_eq!(check_server_config(&plain, "", true, 0).unwrap(), bundle);
+        // signed for the configured key, not for the built-in one
+        assert_eq!(
+            check_server_config(&encoded, &key, false, 0).unwrap(),
+            bundle
+        );
+        assert!(check_server_config(&encoded, "", true, 0).is_err());
+        // tampered
+        let mut tampered = signed.clone();
+        let n = tampered.len();
+        tampered[n - 2] ^= 0x01;
+        let tampered = URL_SAFE_NO_PAD.encode(&tampered);
+        assert!(check_server_config(&tampered, &key, false, 0).is_err());
+        // downgraded
+        assert_eq!(
+            check_server_config(&encoded, &key, false, 2).unwrap(),
+            bundle
+        );
+        assert!(check_server_config(&encoded, &key, false, 3).is_err());
+        assert!(check_server_config(&plain, "", true, 3).is_err());
+    }
+
+    #[test]
+    fn test_sign_exported_server_config() {
+        let (pk, sk) = sign::gen_keypair();
+        let key = crate::encode64(pk.0);
+        let bundle = ServerConfigBundle {
+            version: 5,
+            rendezvous_server: "rs.example.net".to_owned(),
+            key: key.clone(),
+            ..Default::default()
+        };
+        // as exported with the key configured
+        let exported = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&bundle).unwrap());
+        assert!(check_server_config(&exported, &key, true, 5).is_err());
+        let signed = sign_server_config(&exported, &sk).unwrap();
+        assert_eq!(
+            check_server_config(&signed, &key, false, 5).unwrap(),
+            bundle
+        );
+        // signed by another key
+        let (_, sk2) = sign::gen_keypair();
+        let signed = sign_server_config(&exported, &sk2).unwrap();
+        assert!(check_server_config(&signed, &key, false, 5).is_err());
+    }
 }
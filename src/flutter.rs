@@ -2010,6 +2010,8 @@ pub(super) mod async_tasks {
     async fn start_flutter_async_runner_() {
         let (tx_onlines, mut rx_onlines) = unbounded_channel::<Vec<String>>();
         TX_QUERY_ONLINES.lock().unwrap().replace(tx_onlines);
+        #[cfg(not(any(target_os = "ios")))]
+        let mut poller = crate::rendezvous_mediator::OnlinePoller::default();
 
         loop {
             select! {
@@ -2017,7 +2019,7 @@ pub(super) mod async_tasks {
                     match ids {
                         Some(_ids) => {
                             #[cfg(not(any(target_os = "ios")))]
-                            crate::rendezvous_mediator::query_online_states(_ids, handle_query_onlines).await
+                            poller.query(_ids, handle_query_onlines).await
                         }
                         None => {
                             break;
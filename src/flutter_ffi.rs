@@ -823,6 +823,10 @@ pub fn main_test_if_valid_server(server: String, test_with_proxy: bool) -> Strin
     test_if_valid_server(server, test_with_proxy)
 }
 
+pub fn main_check_proxy(proxy: String, username: String, password: String) -> SyncReturn<String> {
+    SyncReturn(check_proxy(proxy, username, password))
+}
+
 pub fn main_set_socks(proxy: String, username: String, password: String) {
     set_socks(proxy, username, password)
 }
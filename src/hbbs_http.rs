@@ -9,6 +9,9 @@ pub mod record_upload;
 pub mod sync;
 pub use http_client::create_http_client;
 pub use http_client::create_http_client_async;
+pub use http_client::create_http_client_async_with_proxy;
+pub use http_client::create_http_client_with_proxy;
+pub use http_client::{parse_proxy, ProxyInfo};
 
 #[derive(Debug)]
 pub enum HbbHttpResponse<T> {
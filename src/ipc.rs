@@ -242,6 +242,8 @@ pub enum Data {
     // Although the key is not neccessary, it is used to avoid hardcoding the key.
     WaylandScreencastRestoreToken((String, String)),
     HwCodecConfig(Option<String>),
+    // The peers announced on the lan since the last request, see `lan::take_announced_peers`.
+    LanAnnouncements(Option<Vec<config::DiscoveryPeer>>),
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -389,6 +391,11 @@ async fn handle(data: Data, stream: &mut Connection) {
                 std::process::exit(-1); // to make sure --server luauchagent process can restart because SuccessfulExit used
             }
         }
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        Data::LanAnnouncements(None) => {
+            let peers = crate::lan::take_announced_peers();
+            allow_err!(stream.send(&Data::LanAnnouncements(Some(peers))).await);
+        }
         Data::OnlineStatus(_) => {
             let x = config::get_online_state();
             let confirmed = Config::get_key_confirmed();
@@ -22,6 +22,14 @@ use std::{
 
 type Message = RendezvousMessage;
 
+// Announced peers kept until the ui takes them, and only announced ones kept in the lan peers.
+const MAX_ANNOUNCED_PEERS: usize = 64;
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+lazy_static::lazy_static! {
+    static ref ANNOUNCED_PEERS: std::sync::Mutex<Vec<config::DiscoveryPeer>> = Default::default();
+}
+
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub(super) fn start_listening() -> ResultType<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], get_broadcast_port()));
@@ -34,26 +42,18 @@ pub(super) fn start_listening() -> ResultType<()> {
             if let Ok(msg_in) = Message::parse_from_bytes(&buf[0..len]) {
                 match msg_in.union {
                     Some(rendezvous_message::Union::PeerDiscovery(p)) => {
-                        if p.cmd == "ping"
-                            && config::option2bool(
-                                "enable-lan-discovery",
-                                &Config::get_option("enable-lan-discovery"),
-                            )
-                        {
+                        if p.cmd == "ping" && is_discovery_enabled() {
                             if let Some(self_addr) = get_ipaddr_by_peer(&addr) {
                                 let mut msg_out = Message::new();
-                                let peer = PeerDiscovery {
-                                    cmd: "pong".to_owned(),
-                                    mac: get_mac(&self_addr),
-                                    id: Config::get_id(),
-                                    hostname: whoami::hostname(),
-                                    username: crate::platform::get_active_username(),
-                                    platform: whoami::platform().to_string(),
-                                    ..Default::default()
-                                };
-                                msg_out.set_peer_discovery(peer);
+                                msg_out.set_peer_discovery(presence(&self_addr));
                                 socket.send_to(&msg_out.write_to_bytes()?, addr).ok();
                             }
+                        } else if p.cmd == "pong"
+                            && p.id != Config::get_id()
+                            && is_discovery_enabled()
+                        {
+                            // an announcement, see `announce`
+                            on_announcement(p, addr);
                         }
                     }
                     _ => {}
@@ -63,6 +63,148 @@ pub(super) fn start_listening() -> ResultType<()> {
     }
 }
 
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+#[inline]
+fn is_discovery_enabled() -> bool {
+    config::option2bool(
+        "enable-lan-discovery",
+        &Config::get_option("enable-lan-discovery"),
+    )
+}
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn presence(self_addr: &IpAddr) -> PeerDiscovery {
+    PeerDiscovery {
+        cmd: "pong".to_owned(),
+        mac: get_mac(self_addr),
+        id: Config::get_id(),
+        hostname: whoami::hostname(),
+        username: crate::platform::get_active_username(),
+        platform: whoami::platform().to_string(),
+        ..Default::default()
+    }
+}
+
+/// Broadcasts an unsolicited pong, so that the lan listeners of other devices find this one
+/// without a discovery ping of their own, e.g. while the rendezvous servers are unreachable.
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub(super) fn announce() -> ResultType<()> {
+    if !is_discovery_enabled() {
+        return Ok(());
+    }
+    let sockets = create_broadcast_sockets();
+    if sockets.is_empty() {
+        bail!("Found no bindable ipv4 addresses");
+    }
+    let maddr = SocketAddr::from(([255, 255, 255, 255], get_broadcast_port()));
+    for socket in &sockets {
+        let Ok(local_addr) = socket.local_addr() else {
+            continue;
+        };
+        let mut msg_out = Message::new();
+        msg_out.set_peer_discovery(presence(&local_addr.ip()));
+        allow_err!(socket.send_to(&msg_out.write_to_bytes()?, maddr));
+    }
+    log::debug!("lan presence announced");
+    Ok(())
+}
+
+// The service keeps the announcements in memory until the ui takes them, the lan peers it shows
+// are in the config of the ui user.
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn on_announcement(p: PeerDiscovery, addr: SocketAddr) {
+    let peer = config::DiscoveryPeer {
+        id: p.id,
+        ip_mac: HashMap::from([(addr.ip().to_string(), p.mac)]),
+        username: p.username,
+        hostname: p.hostname,
+        platform: p.platform,
+        online: true,
+        announced: true,
+    };
+    queue_announcement(&mut ANNOUNCED_PEERS.lock().unwrap(), peer);
+}
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn queue_announcement(pending: &mut Vec<config::DiscoveryPeer>, peer: config::DiscoveryPeer) {
+    if let Some(queued) = pending.iter_mut().find(|x| x.is_same_peer(&peer)) {
+        queued.ip_mac.extend(peer.ip_mac);
+    } else if pending.len() < MAX_ANNOUNCED_PEERS {
+        pending.push(peer);
+    } else {
+        log::debug!("Too many lan announcements, skip the one of {}", peer.id);
+    }
+}
+
+/// The peers announced since the last call, see `store_announced_peers`.
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub fn take_announced_peers() -> Vec<config::DiscoveryPeer> {
+    std::mem::take(&mut ANNOUNCED_PEERS.lock().unwrap())
+}
+
+/// Adds the peers announced to the service to the lan peers of this user, with one write for all
+/// of them. They are not trusted by `is_lan_peer` until they answer a discovery.
+pub fn store_announced_peers(announced: Vec<config::DiscoveryPeer>) {
+    if announced.is_empty() {
+        return;
+    }
+    let mut recent = config::RecentLanPeers::load();
+    let ttl = get_lan_peer_ttl();
+    for peer in &announced {
+        for ip in peer.ip_mac.keys() {
+            recent.insert(&peer.id, ip, hbb_common::get_time(), ttl);
+        }
+    }
+    recent.store();
+    let mut lan_peers = config::LanPeers::load();
+    merge_announced_peers(&mut lan_peers.peers, announced);
+    config::LanPeers::store(&lan_peers.peers);
+    #[cfg(feature = "flutter")]
+    crate::flutter_ffi::main_load_lan_peers();
+}
+
+// A peer already found by a discovery stays trusted, at most `MAX_ANNOUNCED_PEERS` only
+// announced ones are kept.
+fn merge_announced_peers(
+    peers: &mut Vec<config::DiscoveryPeer>,
+    announced: Vec<config::DiscoveryPeer>,
+) {
+    for mut peer in announced {
+        if let Some(pos) = peers.iter().position(|x| x.is_same_peer(&peer)) {
+            let known = peers.remove(pos);
+            peer.announced = known.announced;
+            let mut ip_mac = known.ip_mac;
+            ip_mac.extend(peer.ip_mac);
+            peer.ip_mac = ip_mac;
+        }
+        peers.insert(0, peer);
+    }
+    let mut n = 0;
+    peers.retain(|x| {
+        n += x.announced as usize;
+        !x.announced || n <= MAX_ANNOUNCED_PEERS
+    });
+}
+
+// `lan-peer-ttl` in seconds, how long a peer stays in `recent_lan_peers` after it was last seen.
+fn get_lan_peer_ttl() -> i64 {
+    const DEFAULT_LAN_PEER_TTL: i64 = 7 * 24 * 3600;
+    let ttl = config::Config::get_option(config::keys::OPTION_LAN_PEER_TTL)
+        .parse::<i64>()
+        .ok()
+        .filter(|x| *x > 0)
+        .unwrap_or(DEFAULT_LAN_PEER_TTL);
+    ttl.saturating_mul(1000)
+}
+
+/// The lan peers seen within `lan-peer-ttl`, most recent first, e.g. to fill the lan list on
+/// launch before a discovery finishes.
+pub fn recent_lan_peers() -> Vec<config::LanPeer> {
+    let mut recent = config::RecentLanPeers::load();
+    recent.expire(hbb_common::get_time(), get_lan_peer_ttl());
+    recent.peers
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn discover() -> ResultType<()> {
     let sockets = send_query()?;
@@ -133,6 +275,34 @@ fn get_mac_by_ip(ip: &IpAddr) -> ResultType<String> {
     bail!("No interface found for ip: {:?}", ip);
 }
 
+/// Whether the last lan discovery found `id` at an address in the subnet of a local interface,
+/// peers which did not answer it are stale, and the ones only announced are not trusted.
+pub fn is_lan_peer(id: &str) -> bool {
+    let subnets: Vec<_> = default_net::get_interfaces()
+        .into_iter()
+        .flat_map(|interface| interface.ipv4)
+        .map(|net| (net.addr, net.prefix_len))
+        .collect();
+    config::LanPeers::load()
+        .peers
+        .iter()
+        .filter(|peer| peer.online && !peer.announced && peer.id == id)
+        .flat_map(|peer| peer.ip_mac.keys())
+        .filter_map(|ip| ip.parse::<Ipv4Addr>().ok())
+        .any(|ip| {
+            subnets
+                .iter()
+                .any(|(addr, prefix_len)| is_same_subnet(*addr, *prefix_len, ip))
+        })
+}
+
+fn is_same_subnet(addr: Ipv4Addr, prefix_len: u8, ip: Ipv4Addr) -> bool {
+    let mask = u32::MAX
+        .checked_shl(32 - prefix_len.min(32) as u32)
+        .unwrap_or(0);
+    u32::from(addr) & mask == u32::from(ip) & mask
+}
+
 // Mainly from https://github.com/shellrow/default-net/blob/cf7ca24e7e6e8e566ed32346c9cfddab3f47e2d6/src/interface/shared.rs#L4
 fn get_ipaddr_by_peer<A: ToSocketAddrs>(peer: A) -> Option<IpAddr> {
     let socket = match UdpSocket::bind("0.0.0.0:0") {
@@ -246,6 +416,7 @@ fn wait_response(
                                     hostname: p.hostname.clone(),
                                     platform: p.platform.clone(),
                                     online: true,
+                                    announced: false,
                                 }));
                             }
                         }
@@ -282,12 +453,17 @@ async fn handle_received_peers(mut rx: UnboundedReceiver<config::DiscoveryPeer>)
         peer.online = false;
     });
 
+    let mut recent = config::RecentLanPeers::load();
+    let ttl = get_lan_peer_ttl();
     let mut response_set = HashSet::new();
     let mut last_write_time = Instant::now() - std::time::Duration::from_secs(4);
     loop {
         tokio::select! {
             data = rx.recv() => match data {
                 Some(mut peer) => {
+                    for ip in peer.ip_mac.keys() {
+                        recent.insert(&peer.id, ip, hbb_common::get_time(), ttl);
+                    }
                     let in_response_set = !response_set.insert(peer.id.clone());
                     if let Some(pos) = peers.iter().position(|x| x.is_same_peer(&peer) ) {
                         let peer1 = peers.remove(pos);
@@ -312,7 +488,46 @@ async fn handle_received_peers(mut rx: UnboundedReceiver<config::DiscoveryPeer>)
     }
 
     config::LanPeers::store(&peers);
+    recent.store();
     #[cfg(feature = "flutter")]
     crate::flutter_ffi::main_load_lan_peers();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_announced_peers() {
+        let peer = |id: &str, ip: &str, announced| config::DiscoveryPeer {
+            id: id.to_owned(),
+            ip_mac: HashMap::from([(ip.to_owned(), "".to_owned())]),
+            online: true,
+            announced,
+            ..Default::default()
+        };
+        let mut peers = vec![peer("1", "192.168.1.2", false)];
+        merge_announced_peers(
+            &mut peers,
+            vec![
+                peer("1", "192.168.1.3", true),
+                peer("2", "192.168.1.4", true),
+            ],
+        );
+        // a discovered peer stays trusted, an announced one is not
+        assert_eq!(peers.len(), 2);
+        let discovered = peers.iter().find(|x| x.id == "1").unwrap();
+        assert!(!discovered.announced);
+        assert_eq!(discovered.ip_mac.len(), 2);
+        assert!(peers.iter().find(|x| x.id == "2").unwrap().announced);
+        // only the latest announced ones are kept
+        let flood = (0..MAX_ANNOUNCED_PEERS + 10)
+            .map(|i| peer(&format!("spoofed{}", i), "192.168.1.5", true))
+            .collect();
+        merge_announced_peers(&mut peers, flood);
+        assert_eq!(peers.len(), MAX_ANNOUNCED_PEERS + 1);
+        assert!(peers.iter().any(|x| x.id == "1"));
+        assert!(!peers.iter().any(|x| x.id == "2"));
+    }
+}
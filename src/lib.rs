@@ -18,6 +18,8 @@ mod lan;
 mod rendezvous_mediator;
 #[cfg(not(any(target_os = "ios")))]
 pub use self::rendezvous_mediator::*;
+#[cfg(all(feature = "proto-capture", not(any(target_os = "ios"))))]
+pub mod rendezvous_capture;
 /// cbindgen:ignore
 pub mod common;
 #[cfg(not(any(target_os = "ios")))]
@@ -0,0 +1,212 @@
+// Capture of the rendezvous traffic for protocol testing, built with the `proto-capture` feature.
+//
+// Set `RENDEZVOUS_CAPTURE` to a file path to record every rendezvous message sent or received
+// by the mediator, with the key material cleared. A record is
+// `direction (u8) | time in ms (i64) | length (u32) | message`, all little endian.
+// `RendezvousMediator::replay_capture` feeds a recorded session into the mediator again.
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+};
+
+use hbb_common::{bail, log, protobuf::Message as _, rendezvous_proto::*, ResultType};
+
+const CAPTURE_ENV: &str = "RENDEZVOUS_CAPTURE";
+const RECORD_HEADER_LEN: usize = 1 + 8 + 4;
+
+lazy_static::lazy_static! {
+    static ref CAPTURE_FILE: Mutex<Option<File>> = Mutex::new(open_capture_file());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming = 0,
+    Outgoing = 1,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedMessage {
+    pub direction: Direction,
+    pub time: i64,
+    pub message: RendezvousMessage,
+}
+
+fn open_capture_file() -> Option<File> {
+    let path = std::env::var(CAPTURE_ENV).ok()?;
+    if path.is_empty() {
+        return None;
+    }
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => {
+            log::info!("Capture rendezvous traffic to {}", path);
+            Some(file)
+        }
+        Err(err) => {
+            log::error!("Failed to open rendezvous capture file {}: {}", path, err);
+            None
+        }
+    }
+}
+
+fn encode_record(direction: Direction, time: i64, bytes: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + bytes.len());
+    record.push(direction as u8);
+    record.extend(time.to_le_bytes());
+    record.extend((bytes.len() as u32).to_le_bytes());
+    record.extend(bytes);
+    record
+}
+
+// Keys, signatures, licence keys and tokens are cleared, the rest is recorded as it is.
+fn strip_key_material(msg: &mut RendezvousMessage) {
+    match msg.union.as_mut() {
+        Some(rendezvous_message::Union::RegisterPk(rpk)) => {
+            rpk.pk.clear();
+            rpk.uuid.clear();
+        }
+        Some(rendezvous_message::Union::PunchHoleResponse(phr)) => phr.pk.clear(),
+        Some(rendezvous_message::Union::RelayResponse(rr)) => {
+            if let Some(relay_response::Union::Pk(pk)) = rr.union.as_mut() {
+                pk.clear();
+            }
+        }
+        Some(rendezvous_message::Union::PunchHoleRequest(phr)) => {
+            phr.licence_key.clear();
+            phr.token.clear();
+        }
+        Some(rendezvous_message::Union::RequestRelay(rr)) => {
+            rr.licence_key.clear();
+            rr.token.clear();
+        }
+        Some(rendezvous_message::Union::OnlineRequest(req)) => req.signature.clear(),
+        Some(rendezvous_message::Union::KeyExchange(ke)) => ke.keys.clear(),
+        _ => {}
+    }
+}
+
+pub fn capture_message(direction: Direction, msg: &RendezvousMessage) {
+    let mut lock = CAPTURE_FILE.lock().unwrap();
+    let Some(file) = lock.as_mut() else {
+        return;
+    };
+    let mut msg = msg.clone();
+    strip_key_material(&mut msg);
+    let Ok(bytes) = msg.write_to_bytes() else {
+        return;
+    };
+    let record = encode_record(direction, hbb_common::get_time(), &bytes);
+    if let Err(err) = file.write_all(&record) {
+        log::error!("Failed to write rendezvous capture: {}", err);
+        *lock = None;
+    }
+}
+
+pub fn parse_capture(mut data: &[u8]) -> ResultType<Vec<CapturedMessage>> {
+    let mut res = Vec::new();
+    while !data.is_empty() {
+        if data.len() < RECORD_HEADER_LEN {
+            bail!("Truncated capture record header");
+        }
+        let direction = match data[0] {
+            0 => Direction::Incoming,
+            1 => Direction::Outgoing,
+            x => bail!("Invalid capture direction {}", x),
+        };
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&data[1..9]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&data[9..RECORD_HEADER_LEN]);
+        let len = u32::from_le_bytes(len) as usize;
+        data = &data[RECORD_HEADER_LEN..];
+        if data.len() < len {
+            bail!("Truncated capture record");
+        }
+        res.push(CapturedMessage {
+            direction,
+            time: i64::from_le_bytes(time),
+            message: RendezvousMessage::parse_from_bytes(&data[..len])?,
+        });
+        data = &data[len..];
+    }
+    Ok(res)
+}
+
+pub fn load_capture(path: &str) -> ResultType<Vec<CapturedMessage>> {
+    parse_capture(&std::fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_capture() {
+        let mut register = RendezvousMessage::new();
+        register.set_register_peer(RegisterPeer {
+            id: "123456789".to_owned(),
+            serial: 3,
+            ..Default::default()
+        });
+        let mut response = RendezvousMessage::new();
+        response.set_register_peer_response(RegisterPeerResponse {
+            request_pk: true,
+            ..Default::default()
+        });
+        let mut data = encode_record(
+            Direction::Outgoing,
+            1000,
+            &register.write_to_bytes().unwrap(),
+        );
+        data.extend(encode_record(
+            Direction::Incoming,
+            1020,
+            &response.write_to_bytes().unwrap(),
+        ));
+        let captured = parse_capture(&data).unwrap();
+        assert_eq!(
+            captured,
+            vec![
+                CapturedMessage {
+                    direction: Direction::Outgoing,
+                    time: 1000,
+                    message: register,
+                },
+                CapturedMessage {
+                    direction: Direction::Incoming,
+                    time: 1020,
+                    message: response,
+                },
+            ]
+        );
+        assert!(parse_capture(&data[..data.len() - 1]).is_err());
+        assert!(parse_capture(&data[..RECORD_HEADER_LEN - 1]).is_err());
+        assert_eq!(parse_capture(&[]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_strip_key_material() {
+        let mut msg = RendezvousMessage::new();
+        msg.set_register_pk(RegisterPk {
+            id: "123456789".to_owned(),
+            uuid: vec![1; 16].into(),
+            pk: vec![2; 32].into(),
+            ..Default::default()
+        });
+        strip_key_material(&mut msg);
+        let rpk = msg.register_pk();
+        assert_eq!(rpk.id, "123456789");
+        assert!(rpk.uuid.is_empty() && rpk.pk.is_empty());
+
+        let mut rr = RelayResponse {
+            relay_server: "relay.example.net".to_owned(),
+            ..Default::default()
+        };
+        rr.set_pk(vec![3; 64].into());
+        let mut msg = RendezvousMessage::new();
+        msg.set_relay_response(rr);
+        strip_key_material(&mut msg);
+        assert!(msg.relay_response().pk().is_empty());
+        assert_eq!(msg.relay_response().relay_server, "relay.example.net");
+    }
+}
@@ -0,0 +1,293 @@
+use super::*;
+
+const DEFAULT_DIRECT_ACCESS_MAX_CONCURRENT: usize = 64;
+// Prefixes the nonce of the `direct-psk` challenge, so that a client can tell it from a message.
+const DIRECT_PSK_MAGIC: &[u8] = b"RDPSK1";
+const DIRECT_PSK_NONCE_LEN: usize = 32;
+const DIRECT_PSK_TIMEOUT: u64 = 5_000;
+
+lazy_static::lazy_static! {
+    static ref DIRECT_SERVER_OBSERVER: std::sync::RwLock<Option<DirectServerObserver>> = Default::default();
+}
+
+type DirectServerObserver = Box<dyn Fn(&DirectServerEvent) + Send + Sync>;
+
+// `direct-access-max-concurrent`, 0 or invalid for the default.
+fn get_direct_access_max_concurrent() -> usize {
+    match Config::get_option(keys::OPTION_DIRECT_ACCESS_MAX_CONCURRENT).parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => DEFAULT_DIRECT_ACCESS_MAX_CONCURRENT,
+    }
+}
+
+// Slots of the direct connections in flight. The handshake runs in the task of the connection,
+// so a slot is only released when the connection ends.
+struct DirectSlots {
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+}
+
+impl DirectSlots {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    // `None` if all slots are taken. A changed limit applies to new connections only,
+    // the ones in flight keep the slots of the old limit.
+    fn try_acquire(&mut self, limit: usize) -> Option<OwnedSemaphorePermit> {
+        if limit != self.limit {
+            *self = Self::new(limit);
+        }
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectServerEvent {
+    Listening(u16),
+    BindFailed { port: u16, error: String },
+}
+
+/// Calls `f` whenever the direct server binds its port or fails to, including the rebinds after
+/// `direct-access-port` changes, e.g. to show the port or the bind error in the ui.
+pub fn set_direct_server_observer<F>(f: F)
+where
+    F: Fn(&DirectServerEvent) + Send + Sync + 'static,
+{
+    *DIRECT_SERVER_OBSERVER.write().unwrap() = Some(Box::new(f));
+}
+
+pub fn clear_direct_server_observer() {
+    *DIRECT_SERVER_OBSERVER.write().unwrap() = None;
+}
+
+fn notify_direct_server(event: DirectServerEvent) {
+    if let Some(f) = DIRECT_SERVER_OBSERVER.read().unwrap().as_ref() {
+        f(&event);
+    }
+}
+
+async fn bind_direct_server(port: i32) -> Option<tokio::net::TcpListener> {
+    match hbb_common::tcp::listen_any(port as _, socket_client::get_tos()).await {
+        Ok(l) => {
+            let addr = l.local_addr();
+            log::info!("Direct server listening on: {:?}", addr);
+            let bound = addr.map(|x| x.port()).unwrap_or(port as _);
+            notify_direct_server(DirectServerEvent::Listening(bound));
+            Some(l)
+        }
+        Err(err) => {
+            log::error!(
+                "Failed to start direct server on port: {}, error: {}",
+                port,
+                err
+            );
+            notify_direct_server(DirectServerEvent::BindFailed {
+                port: port as _,
+                error: err.to_string(),
+            });
+            None
+        }
+    }
+}
+
+// `direct-psk`, the secret incoming direct connections must prove to know before the handshake,
+// empty to accept any. Clients set the same option to answer the challenge.
+fn get_direct_psk() -> String {
+    Config::get_option(keys::OPTION_DIRECT_PSK)
+}
+
+fn direct_psk_key(psk: &str) -> auth::Key {
+    auth::Key(sha256::hash(psk.as_bytes()).0)
+}
+
+// Sends a random nonce and expects its HMAC under `psk` back.
+async fn verify_direct_psk(stream: &mut FramedStream, psk: &str) -> ResultType<()> {
+    let nonce = hbb_common::sodiumoxide::randombytes::randombytes(DIRECT_PSK_NONCE_LEN);
+    stream
+        .send_bytes([DIRECT_PSK_MAGIC, &nonce].concat().into())
+        .await?;
+    let bytes = match stream.next_timeout(DIRECT_PSK_TIMEOUT).await {
+        Some(Ok(bytes)) => bytes,
+        Some(Err(err)) => bail!("Failed to read the pre-shared key proof: {}", err),
+        None => bail!("Timeout waiting for the pre-shared key proof"),
+    };
+    match auth::Tag::from_slice(&bytes) {
+        Some(tag) if auth::verify(&tag, &nonce, &direct_psk_key(psk)) => Ok(()),
+        _ => bail!("Wrong pre-shared key proof"),
+    }
+}
+
+/// Answers the challenge of a direct server with `direct-psk` set, if this side has it set too.
+/// The challenge is the first frame such a server sends, before the usual handshake.
+pub(crate) async fn answer_direct_psk(stream: &mut FramedStream) -> ResultType<()> {
+    let psk = get_direct_psk();
+    if psk.is_empty() {
+        return Ok(());
+    }
+    answer_psk_challenge(stream, &psk).await
+}
+
+async fn answer_psk_challenge(stream: &mut FramedStream, psk: &str) -> ResultType<()> {
+    let bytes = match stream.next_timeout(DIRECT_PSK_TIMEOUT).await {
+        Some(Ok(bytes)) => bytes,
+        Some(Err(err)) => bail!("Failed to read the pre-shared key challenge: {}", err),
+        None => bail!("Timeout waiting for the pre-shared key challenge"),
+    };
+    let Some(nonce) = bytes.strip_prefix(DIRECT_PSK_MAGIC) else {
+        bail!("The peer does not ask for the pre-shared key, unset direct-psk to connect to it");
+    };
+    let tag = auth::authenticate(nonce, &direct_psk_key(psk));
+    stream.send_bytes(tag.0.to_vec().into()).await
+}
+
+pub(super) async fn direct_server(server: ServerPtr) {
+    let mut listener = None;
+    let mut port = 0;
+    // Kept when the listener is closed, connections in flight outlive it.
+    let mut slots = DirectSlots::new(get_direct_access_max_concurrent());
+    loop {
+        let disabled = Config::get_option("direct-server").is_empty()
+            || !Config::get_option("stop-service").is_empty();
+        if !disabled && listener.is_none() {
+            port = get_direct_port();
+            listener = bind_direct_server(port).await;
+            if listener.is_none() {
+                loop {
+                    if port != get_direct_port() {
+                        break;
+                    }
+                    sleep(1.).await;
+                }
+            }
+        }
+        if let Some(l) = listener.as_mut() {
+            if disabled || port != get_direct_port() {
+                log::info!("Exit direct access listen");
+                listener = None;
+                continue;
+            }
+            if let Ok(Ok((stream, addr))) = hbb_common::timeout(1000, l.accept()).await {
+                let Some(permit) = slots.try_acquire(get_direct_access_max_concurrent()) else {
+                    log::warn!(
+                        "Reject direct access from {}, {} connections are in flight already",
+                        addr,
+                        slots.limit
+                    );
+                    continue;
+                };
+                stream.set_nodelay(true).ok();
+                log::info!("direct access from {}", addr);
+                let local_addr = stream
+                    .local_addr()
+                    .unwrap_or(Config::get_any_listen_addr(true));
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let mut stream = hbb_common::Stream::from(stream, local_addr);
+                    let psk = get_direct_psk();
+                    if !psk.is_empty() {
+                        if let Err(err) = verify_direct_psk(&mut stream, &psk).await {
+                            log::warn!("Reject direct access from {}: {}", addr, err);
+                            return;
+                        }
+                    }
+                    let _path = ConnectionPathGuard::new(addr, ConnectionPath::Direct);
+                    allow_err!(
+                        crate::server::create_tcp_connection(server, stream, addr, false).await
+                    );
+                });
+            } else {
+                sleep(0.1).await;
+            }
+        } else {
+            sleep(1.).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hbb_common::tokio;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_direct_server_observer() {
+        use super::*;
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let cloned = events.clone();
+        set_direct_server_observer(move |event| cloned.lock().unwrap().push(event.clone()));
+        let listener = bind_direct_server(0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        // taken by a socket without SO_REUSEPORT
+        let taken = std::net::TcpListener::bind(("0.0.0.0", port)).unwrap();
+        assert!(bind_direct_server(port as _).await.is_none());
+        drop(taken);
+        clear_direct_server_observer();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], DirectServerEvent::Listening(port));
+        match &events[1] {
+            DirectServerEvent::BindFailed {
+                port: failed,
+                error,
+            } => {
+                assert_eq!(*failed, port);
+                assert!(!error.is_empty());
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_direct_psk() {
+        use super::*;
+        let addr: SocketAddr = "127.0.0.1:21118".parse().unwrap();
+        let handshake = |server_psk: &'static str, client_psk: &'static str| async move {
+            let (a, b) = tokio::io::duplex(1024);
+            let mut server = FramedStream::from(a, addr);
+            let mut client = FramedStream::from(b, addr);
+            let (verified, _) = tokio::join!(
+                verify_direct_psk(&mut server, server_psk),
+                answer_psk_challenge(&mut client, client_psk)
+            );
+            verified.is_ok()
+        };
+        assert!(handshake("secret", "secret").await);
+        assert!(!handshake("secret", "wrong").await);
+        // a client without the key does not answer the challenge
+        let (a, b) = tokio::io::duplex(1024);
+        let mut server = FramedStream::from(a, addr);
+        let mut client = FramedStream::from(b, addr);
+        let (verified, challenge) = tokio::join!(verify_direct_psk(&mut server, "secret"), async {
+            let challenge = client.next().await;
+            client.send_bytes(vec![0u8; 10].into()).await.ok();
+            challenge
+        });
+        assert!(verified.is_err());
+        assert!(challenge.unwrap().unwrap().starts_with(DIRECT_PSK_MAGIC));
+    }
+
+    #[test]
+    fn test_direct_slots() {
+        use super::*;
+        let mut slots = DirectSlots::new(2);
+        let first = slots.try_acquire(2).unwrap();
+        let second = slots.try_acquire(2).unwrap();
+        assert!(slots.try_acquire(2).is_none());
+        drop(first);
+        let third = slots.try_acquire(2).unwrap();
+        assert!(slots.try_acquire(2).is_none());
+        // a new limit does not count the connections in flight
+        let mut raised: Vec<_> = (0..3).map_while(|_| slots.try_acquire(3)).collect();
+        assert_eq!(raised.len(), 3);
+        assert!(slots.try_acquire(3).is_none());
+        raised.pop();
+        assert!(slots.try_acquire(3).is_some());
+        drop((second, third));
+    }
+}
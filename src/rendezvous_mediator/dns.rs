@@ -0,0 +1,299 @@
+use super::*;
+
+const DEFAULT_DNS_CACHE_TTL: u64 = 300_000;
+const UDP_RACE_TIMEOUT: u64 = 2_000;
+
+lazy_static::lazy_static! {
+    static ref DNS_OVERRIDE: std::sync::RwLock<Option<DnsOverride>> = Default::default();
+    static ref DNS_CACHE: std::sync::Mutex<DnsCache> = Default::default();
+    static ref UDP_FAMILIES: std::sync::Mutex<HashMap<String, bool>> = Default::default();
+}
+
+type DnsOverride = Box<dyn Fn(&str) -> Option<Vec<SocketAddr>> + Send + Sync>;
+
+/// Resolve the rendezvous hosts with `f` instead of the system resolver, e.g. for split-horizon
+/// dns or to point a host at a local mock in tests. `f` gets the host with the port, e.g.
+/// "rs.example.com:21116", hosts it returns `None` for are resolved as usual.
+pub fn set_dns_override<F>(f: F)
+where
+    F: Fn(&str) -> Option<Vec<SocketAddr>> + Send + Sync + 'static,
+{
+    *DNS_OVERRIDE.write().unwrap() = Some(Box::new(f));
+}
+
+pub fn clear_dns_override() {
+    *DNS_OVERRIDE.write().unwrap() = None;
+}
+
+pub(super) fn resolve_override(host: &str) -> Option<Vec<SocketAddr>> {
+    let lock = DNS_OVERRIDE.read().unwrap();
+    let addrs = lock.as_ref()?(host)?;
+    if addrs.is_empty() {
+        return None;
+    }
+    log::debug!("Resolve {} to {:?} by the dns override", host, addrs);
+    Some(addrs)
+}
+
+#[derive(Debug, Default)]
+struct DnsCache {
+    entries: HashMap<String, (Vec<SocketAddr>, Instant)>,
+}
+
+impl DnsCache {
+    fn get(&self, host: &str, ttl: u64) -> Option<Vec<SocketAddr>> {
+        let (addrs, resolved) = self.entries.get(host)?;
+        (resolved.elapsed().as_millis() < ttl as u128).then(|| addrs.clone())
+    }
+
+    fn insert(&mut self, host: &str, addrs: Vec<SocketAddr>) {
+        self.entries
+            .insert(host.to_owned(), (addrs, Instant::now()));
+    }
+
+    // The address in use goes first, the other ones are kept for the race of the udp families.
+    fn prefer(&mut self, host: &str, addr: SocketAddr) {
+        let mut addrs = vec![addr];
+        if let Some((cached, _)) = self.entries.get(host) {
+            if cached.contains(&addr) {
+                addrs.extend(cached.iter().filter(|x| **x != addr));
+            }
+        }
+        self.insert(host, addrs);
+    }
+}
+
+// `rendezvous-dns-cache-ttl` in milliseconds, 0 to disable the cache.
+fn get_dns_cache_ttl() -> u64 {
+    Config::get_option(keys::OPTION_RENDEZVOUS_DNS_CACHE_TTL)
+        .parse()
+        .unwrap_or(DEFAULT_DNS_CACHE_TTL)
+}
+
+// Called once the udp socket of `host` is bound to `addr`.
+pub(super) fn on_udp_bound(host: &str, addr: &TargetAddr<'_>, is_proxy: bool) {
+    let TargetAddr::Ip(addr) = addr else {
+        return;
+    };
+    UDP_FAMILIES
+        .lock()
+        .unwrap()
+        .insert(host.to_owned(), addr.is_ipv4());
+    if !is_proxy && get_dns_cache_ttl() > 0 && resolve_override(host).is_none() {
+        DNS_CACHE.lock().unwrap().prefer(host, *addr);
+    }
+}
+
+/// The family of the udp socket to the rendezvous server `host`, "ipv4" or "ipv6".
+pub fn rendezvous_udp_family(host: &str) -> Option<&'static str> {
+    let host = check_port(host, RENDEZVOUS_PORT);
+    UDP_FAMILIES
+        .lock()
+        .unwrap()
+        .get(&host)
+        .map(|ipv4| if *ipv4 { "ipv4" } else { "ipv6" })
+}
+
+async fn udp_probe(addr: SocketAddr, msg: &Message) -> Option<FramedSocket> {
+    let mut socket = FramedSocket::new(Config::get_any_listen_addr(addr.is_ipv4()))
+        .await
+        .ok()?;
+    socket.send(msg, addr).await.ok()?;
+    Some(socket)
+}
+
+// Happy eyeballs, a RegisterPeer goes over ipv6 and ipv4 at once and the family answering first
+// is kept. `None` if `host` lacks one of the families or neither answers in time. The answer
+// itself is dropped, the mediator registers again on the kept socket.
+pub(super) async fn race_udp_families(
+    host: &str,
+    force_dns_recheck: bool,
+) -> Option<(FramedSocket, TargetAddr<'static>)> {
+    let addrs = resolve_rendezvous_host(host, force_dns_recheck).await?;
+    let v6 = *addrs.iter().find(|x| x.is_ipv6())?;
+    let v4 = *addrs.iter().find(|x| x.is_ipv4())?;
+    let mut msg_out = Message::new();
+    msg_out.set_register_peer(RegisterPeer {
+        id: Config::get_id(),
+        serial: Config::get_serial(),
+        capabilities: client_capabilities(),
+        ..Default::default()
+    });
+    let mut s6 = udp_probe(v6, &msg_out).await?;
+    let mut s4 = udp_probe(v4, &msg_out).await?;
+    let v6_won = hbb_common::timeout(UDP_RACE_TIMEOUT, async {
+        select! {
+            Some(Ok(_)) = s6.next() => Some(true),
+            Some(Ok(_)) = s4.next() => Some(false),
+            else => None,
+        }
+    })
+    .await
+    .ok()??;
+    log::info!(
+        "{} answered over {} first",
+        host,
+        if v6_won { "ipv6" } else { "ipv4" }
+    );
+    if v6_won {
+        Some((s6, TargetAddr::Ip(v6)))
+    } else {
+        Some((s4, TargetAddr::Ip(v4)))
+    }
+}
+
+// The families are raced again, the one kept before may be gone after a network change.
+pub(super) async fn rebind_rendezvous_udp(
+    host: &str,
+    is_proxy: bool,
+    force_dns_recheck: bool,
+) -> ResultType<Option<(FramedSocket, TargetAddr<'static>)>> {
+    if !is_proxy {
+        if let Some(res) = race_udp_families(host, force_dns_recheck).await {
+            return Ok(Some(res));
+        }
+    }
+    let target = rendezvous_udp_target(host, is_proxy, force_dns_recheck);
+    socket_client::rebind_udp_for_via(&target, is_proxy).await
+}
+
+// The addresses of the rendezvous `host`, the dns override wins over the cache, which is skipped
+// with `force_dns_recheck`. `None` if it can not be resolved.
+pub(super) async fn resolve_rendezvous_host(
+    host: &str,
+    force_dns_recheck: bool,
+) -> Option<Vec<SocketAddr>> {
+    if let Some(addrs) = resolve_override(host) {
+        return Some(addrs);
+    }
+    let ttl = get_dns_cache_ttl();
+    if !force_dns_recheck {
+        if let Some(addrs) = DNS_CACHE.lock().unwrap().get(host, ttl) {
+            return Some(addrs);
+        }
+    }
+    match tokio::net::lookup_host(host).await {
+        Ok(addrs) => {
+            let addrs: Vec<_> = addrs.collect();
+            if addrs.is_empty() {
+                return None;
+            }
+            if ttl > 0 {
+                DNS_CACHE.lock().unwrap().insert(host, addrs.clone());
+            }
+            Some(addrs)
+        }
+        Err(e) => {
+            log::debug!("Failed to resolve {}: {}", host, e);
+            None
+        }
+    }
+}
+
+// The udp target of `host`, `host` itself on a cache miss to let the socket pick a reachable
+// address, which is cached then. The proxy resolves the host if there is one.
+pub(super) fn rendezvous_udp_target(host: &str, is_proxy: bool, force_dns_recheck: bool) -> String {
+    let addrs = if let Some(addrs) = resolve_override(host) {
+        Some(addrs)
+    } else if is_proxy || force_dns_recheck {
+        None
+    } else {
+        DNS_CACHE.lock().unwrap().get(host, get_dns_cache_ttl())
+    };
+    addrs
+        .and_then(|addrs| addrs.first().map(|x| x.to_string()))
+        .unwrap_or(host.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use hbb_common::tokio;
+
+    #[tokio::test]
+    async fn test_dns_override() {
+        use super::*;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        set_dns_override(move |host| {
+            if host == "mock.dns.test:21116" {
+                Some(vec![local])
+            } else {
+                None
+            }
+        });
+        assert_eq!(resolve_override("mock.dns.test:21116"), Some(vec![local]));
+        assert_eq!(resolve_override("other.dns.test:21116"), None);
+        let accept = tokio::spawn(async move { listener.accept().await.is_ok() });
+        let conn = connect_rendezvous_tcp("mock.dns.test:21116", "mock.dns.test:21116").await;
+        clear_dns_override();
+        assert!(conn.is_ok());
+        assert!(accept.await.unwrap());
+        assert_eq!(resolve_override("mock.dns.test:21116"), None);
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache() {
+        use super::*;
+        let cached: SocketAddr = "127.0.0.1:8417".parse().unwrap();
+        let mut cache = DnsCache::default();
+        cache.insert("cache.dns.invalid:8417", vec![cached]);
+        assert_eq!(
+            cache.get("cache.dns.invalid:8417", 60_000),
+            Some(vec![cached])
+        );
+        // expired or disabled
+        assert_eq!(cache.get("cache.dns.invalid:8417", 0), None);
+        assert_eq!(cache.get("other.dns.invalid:8417", 60_000), None);
+        DNS_CACHE
+            .lock()
+            .unwrap()
+            .insert("cache.dns.invalid:8417", vec![cached]);
+        assert_eq!(
+            resolve_rendezvous_host("cache.dns.invalid:8417", false).await,
+            Some(vec![cached])
+        );
+        assert_eq!(
+            rendezvous_udp_target("cache.dns.invalid:8417", false, false),
+            "127.0.0.1:8417"
+        );
+        // the recheck goes to the resolver, which does not know the host
+        assert_eq!(
+            resolve_rendezvous_host("cache.dns.invalid:8417", true).await,
+            None
+        );
+        assert_eq!(
+            rendezvous_udp_target("cache.dns.invalid:8417", false, true),
+            "cache.dns.invalid:8417"
+        );
+        assert_eq!(
+            rendezvous_udp_target("cache.dns.invalid:8417", true, false),
+            "cache.dns.invalid:8417"
+        );
+    }
+
+    #[test]
+    fn test_udp_family() {
+        use super::*;
+        let v6: SocketAddr = "[::1]:8417".parse().unwrap();
+        let v4: SocketAddr = "127.0.0.1:8417".parse().unwrap();
+        let mut cache = DnsCache::default();
+        cache.insert("family.dns.invalid:8417", vec![v6, v4]);
+        cache.prefer("family.dns.invalid:8417", v4);
+        assert_eq!(
+            cache.get("family.dns.invalid:8417", 60_000),
+            Some(vec![v4, v6])
+        );
+        // a stale address is not kept
+        let other: SocketAddr = "127.0.0.2:8417".parse().unwrap();
+        cache.prefer("family.dns.invalid:8417", other);
+        assert_eq!(
+            cache.get("family.dns.invalid:8417", 60_000),
+            Some(vec![other])
+        );
+        assert_eq!(rendezvous_udp_family("family.dns.invalid"), None);
+        on_udp_bound("family.dns.invalid:8417", &TargetAddr::Ip(v6), true);
+        assert_eq!(rendezvous_udp_family("family.dns.invalid"), Some("ipv6"));
+        on_udp_bound("family.dns.invalid:8417", &TargetAddr::Ip(v4), true);
+        assert_eq!(rendezvous_udp_family("family.dns.invalid"), Some("ipv4"));
+    }
+}
@@ -0,0 +1,296 @@
+use super::*;
+
+// Events kept for a slow subscriber, the oldest ones are dropped beyond it.
+pub const CONNECTION_EVENTS_CAPACITY: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref CONNECTION_EVENTS: broadcast::Sender<ConnectionPathEvent> = broadcast::channel(CONNECTION_EVENTS_CAPACITY).0;
+    static ref RENDEZVOUS_CONNECTED: std::sync::Mutex<HashSet<(String, RendezvousTransport)>> = Default::default();
+    static ref RENDEZVOUS_OBSERVER: std::sync::RwLock<Option<RendezvousObserver>> = Default::default();
+}
+
+type RendezvousObserver = Box<dyn Fn(&RendezvousEvent) + Send + Sync>;
+// Set while no rendezvous server is configured.
+static NO_SERVER: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPath {
+    Direct,
+    Relay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPathEvent {
+    Opened(SocketAddr, ConnectionPath),
+    Closed(SocketAddr),
+}
+
+// Records the path of an incoming connection for as long as the connection lives.
+pub(super) struct ConnectionPathGuard(SocketAddr);
+
+impl ConnectionPathGuard {
+    pub(super) fn new(peer_addr: SocketAddr, path: ConnectionPath) -> Self {
+        CONNECTION_PATHS.lock().unwrap().insert(peer_addr, path);
+        emit_connection_event(ConnectionPathEvent::Opened(peer_addr, path));
+        Self(peer_addr)
+    }
+}
+
+impl Drop for ConnectionPathGuard {
+    fn drop(&mut self) {
+        CONNECTION_PATHS.lock().unwrap().remove(&self.0);
+        CONNECTION_RELAYS.lock().unwrap().remove(&self.0);
+        emit_connection_event(ConnectionPathEvent::Closed(self.0));
+    }
+}
+
+// Records the transport of a running rendezvous loop, it is disconnected when the loop ends.
+pub(super) struct ActiveRendezvousGuard(String, RendezvousTransport);
+
+impl ActiveRendezvousGuard {
+    pub(super) fn new(host: &str, transport: RendezvousTransport) -> Self {
+        ACTIVE_RENDEZVOUS
+            .lock()
+            .unwrap()
+            .insert(host.to_owned(), transport);
+        Self(host.to_owned(), transport)
+    }
+}
+
+impl Drop for ActiveRendezvousGuard {
+    fn drop(&mut self) {
+        ACTIVE_RENDEZVOUS.lock().unwrap().remove(&self.0);
+        set_rendezvous_connected(&self.0, self.1, false);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RendezvousEvent {
+    /// Registered to `host`, once per connect.
+    Connected(String, RendezvousTransport),
+    /// A connected `host` stopped answering or its loop ended.
+    Disconnected(String, RendezvousTransport),
+    /// No rendezvous server is configured, it is checked again slowly or on a restart.
+    NoServer,
+}
+
+/// Calls `f` when a rendezvous connection is registered or lost, e.g. to drive a status
+/// indicator instead of guessing from the latency.
+pub fn set_rendezvous_observer<F>(f: F)
+where
+    F: Fn(&RendezvousEvent) + Send + Sync + 'static,
+{
+    *RENDEZVOUS_OBSERVER.write().unwrap() = Some(Box::new(f));
+}
+
+pub fn clear_rendezvous_observer() {
+    *RENDEZVOUS_OBSERVER.write().unwrap() = None;
+}
+
+// The event if `connected` changes the state of `host` over `transport`.
+fn update_rendezvous_connected(
+    connected_set: &mut HashSet<(String, RendezvousTransport)>,
+    host: &str,
+    transport: RendezvousTransport,
+    connected: bool,
+) -> Option<RendezvousEvent> {
+    let key = (host.to_owned(), transport);
+    if connected && connected_set.insert(key) {
+        Some(RendezvousEvent::Connected(host.to_owned(), transport))
+    } else if !connected && connected_set.remove(&key) {
+        Some(RendezvousEvent::Disconnected(host.to_owned(), transport))
+    } else {
+        None
+    }
+}
+
+/// Whether no rendezvous server is configured, e.g. to prompt for one.
+pub fn is_no_server_configured() -> bool {
+    NO_SERVER.load(Ordering::SeqCst)
+}
+
+pub(super) fn set_no_server(no_server: bool) {
+    if NO_SERVER.swap(no_server, Ordering::SeqCst) == no_server || !no_server {
+        return;
+    }
+    log::error!(
+        "No rendezvous server is configured, check again in {:?} or when the servers change",
+        NO_SERVER_RETRY
+    );
+    if let Some(f) = RENDEZVOUS_OBSERVER.read().unwrap().as_ref() {
+        f(&RendezvousEvent::NoServer);
+    }
+}
+
+pub(super) fn set_rendezvous_connected(
+    host: &str,
+    transport: RendezvousTransport,
+    connected: bool,
+) {
+    let event = update_rendezvous_connected(
+        &mut RENDEZVOUS_CONNECTED.lock().unwrap(),
+        host,
+        transport,
+        connected,
+    );
+    if let Some(event) = event {
+        log::info!("{:?}", event);
+        if let Some(f) = RENDEZVOUS_OBSERVER.read().unwrap().as_ref() {
+            f(&event);
+        }
+    }
+}
+
+/// The transport `host` is connected over right now, e.g. whether the auto mode fell back to tcp.
+pub fn active_transport(host: &str) -> Option<RendezvousTransport> {
+    ACTIVE_RENDEZVOUS
+        .lock()
+        .unwrap()
+        .get(&check_port(host, RENDEZVOUS_PORT))
+        .copied()
+}
+
+fn emit_connection_event(event: ConnectionPathEvent) {
+    if connection_event_subscriber_count() > 0 {
+        CONNECTION_EVENTS.send(event).ok();
+    }
+}
+
+pub fn connection_event_subscriber_count() -> usize {
+    CONNECTION_EVENTS.receiver_count()
+}
+
+/// At most `CONNECTION_EVENTS_CAPACITY` events are buffered for a subscriber,
+/// older ones are dropped if it does not keep up and counted in `lagged()`.
+pub fn subscribe_connection_events() -> ConnectionEventReceiver {
+    ConnectionEventReceiver {
+        rx: CONNECTION_EVENTS.subscribe(),
+        lagged: 0,
+    }
+}
+
+pub struct ConnectionEventReceiver {
+    rx: broadcast::Receiver<ConnectionPathEvent>,
+    lagged: u64,
+}
+
+impl ConnectionEventReceiver {
+    pub async fn recv(&mut self) -> Option<ConnectionPathEvent> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    self.lagged += n;
+                    log::warn!(
+                        "Connection event subscriber lagged, {} events dropped, {} in total",
+                        n,
+                        self.lagged
+                    );
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    pub fn lagged(&self) -> u64 {
+        self.lagged
+    }
+}
+
+pub fn get_connection_paths() -> Vec<(SocketAddr, ConnectionPath)> {
+    CONNECTION_PATHS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(addr, path)| (*addr, *path))
+        .collect()
+}
+
+/// The relay server of a relayed connection and where it comes from.
+pub fn get_connection_relay(peer_addr: &SocketAddr) -> Option<(String, RelaySource)> {
+    CONNECTION_RELAYS.lock().unwrap().get(peer_addr).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use hbb_common::tokio;
+
+    #[tokio::test]
+    async fn test_connection_events_lag() {
+        use super::*;
+        let addr: SocketAddr = "127.0.0.1:21118".parse().unwrap();
+        let mut rx = subscribe_connection_events();
+        assert!(connection_event_subscriber_count() > 0);
+        let extra = 10;
+        for _ in 0..CONNECTION_EVENTS_CAPACITY + extra {
+            emit_connection_event(ConnectionPathEvent::Opened(addr, ConnectionPath::Relay));
+        }
+        for _ in 0..CONNECTION_EVENTS_CAPACITY {
+            assert_eq!(
+                rx.recv().await,
+                Some(ConnectionPathEvent::Opened(addr, ConnectionPath::Relay))
+            );
+        }
+        // only the newest events are kept
+        assert_eq!(rx.lagged(), extra as u64);
+        assert!(rx.rx.is_empty());
+    }
+
+    #[test]
+    fn test_active_transport() {
+        use super::*;
+        let host = format!("active.example.com:{}", RENDEZVOUS_PORT);
+        assert_eq!(active_transport("active.example.com"), None);
+        {
+            // as in start_udp
+            let _active = ActiveRendezvousGuard::new(&host, RendezvousTransport::Udp);
+            assert_eq!(
+                active_transport("active.example.com"),
+                Some(RendezvousTransport::Udp)
+            );
+        }
+        assert_eq!(active_transport(&host), None);
+        // as in start_tcp after falling back
+        let active = ActiveRendezvousGuard::new(&host, RendezvousTransport::Tcp);
+        assert_eq!(active_transport(&host), Some(RendezvousTransport::Tcp));
+        drop(active);
+        assert_eq!(active_transport(&host), None);
+    }
+
+    #[test]
+    fn test_rendezvous_connected() {
+        use super::*;
+        let mut set = HashSet::new();
+        let host = "rs.example.com:21116";
+        let udp = RendezvousTransport::Udp;
+        assert_eq!(
+            update_rendezvous_connected(&mut set, host, udp, false),
+            None
+        );
+        assert_eq!(
+            update_rendezvous_connected(&mut set, host, udp, true),
+            Some(RendezvousEvent::Connected(host.to_owned(), udp))
+        );
+        // once per connect, not on every registration
+        assert_eq!(update_rendezvous_connected(&mut set, host, udp, true), None);
+        assert_eq!(
+            update_rendezvous_connected(&mut set, host, RendezvousTransport::Tcp, true),
+            Some(RendezvousEvent::Connected(
+                host.to_owned(),
+                RendezvousTransport::Tcp
+            ))
+        );
+        assert_eq!(
+            update_rendezvous_connected(&mut set, host, udp, false),
+            Some(RendezvousEvent::Disconnected(host.to_owned(), udp))
+        );
+        assert_eq!(
+            update_rendezvous_connected(&mut set, host, udp, false),
+            None
+        );
+        assert_eq!(
+            update_rendezvous_connected(&mut set, host, udp, true),
+            Some(RendezvousEvent::Connected(host.to_owned(), udp))
+        );
+    }
+}
@@ -0,0 +1,633 @@
+use super::*;
+
+const DEFAULT_OFFLINE_GRACE_QUERIES: u32 = 2;
+// About 10 bytes per id, far below what the rendezvous server accepts in one OnlineRequest.
+const DEFAULT_MAX_ONLINE_QUERY_PEERS: usize = 10_000;
+
+lazy_static::lazy_static! {
+    static ref ONLINE_CACHE: std::sync::Mutex<OnlineCache> = Default::default();
+}
+
+pub async fn query_online_states<F: FnOnce(Vec<String>, Vec<String>)>(ids: Vec<String>, f: F) {
+    if let Some((onlines, offlines)) = query_online_states_async(ids).await {
+        f(onlines, offlines)
+    }
+}
+
+/// Polls online states, a peer shown online is only shown offline after it is reported offline
+/// by `offline-grace-queries` consecutive queries. Going online is shown at once.
+#[derive(Debug, Default)]
+pub struct OnlinePoller {
+    // Peers shown online, with the number of consecutive offline reports since.
+    shown_online: HashMap<String, u32>,
+}
+
+impl OnlinePoller {
+    pub async fn query<F: FnOnce(Vec<String>, Vec<String>)>(&mut self, ids: Vec<String>, f: F) {
+        if let Some((onlines, offlines)) = query_online_states_async(ids).await {
+            let (onlines, offlines) = self.debounce(get_offline_grace_queries(), onlines, offlines);
+            f(onlines, offlines)
+        }
+    }
+
+    fn debounce(
+        &mut self,
+        grace: u32,
+        mut onlines: Vec<String>,
+        offlines: Vec<String>,
+    ) -> (Vec<String>, Vec<String>) {
+        for id in onlines.iter() {
+            self.shown_online.insert(id.clone(), 0);
+        }
+        let mut shown_offlines = Vec::new();
+        for id in offlines {
+            match self.shown_online.get_mut(&id) {
+                Some(misses) if *misses + 1 < grace => {
+                    *misses += 1;
+                    onlines.push(id);
+                }
+                _ => {
+                    self.shown_online.remove(&id);
+                    shown_offlines.push(id);
+                }
+            }
+        }
+        (onlines, shown_offlines)
+    }
+}
+
+fn get_offline_grace_queries() -> u32 {
+    match Config::get_option(keys::OPTION_OFFLINE_GRACE_QUERIES).parse::<u32>() {
+        Ok(n) if n > 0 => n,
+        _ => DEFAULT_OFFLINE_GRACE_QUERIES,
+    }
+}
+
+/// Returns `(onlines, offlines)`, or `None` if the query times out, the mediator is exiting or
+/// more than `max-online-query-peers` (10000 by default) ids are queried at once.
+pub async fn query_online_states_async(ids: Vec<String>) -> Option<(Vec<String>, Vec<String>)> {
+    let test = false;
+    if test {
+        sleep(1.5).await;
+        let mut onlines = ids;
+        let offlines = onlines.drain((onlines.len() / 2)..).collect();
+        return Some((onlines, offlines));
+    }
+    match query_online_response(&ids, false).await? {
+        Some(res) => Some(parse_online_states(&ids, &res.states)),
+        None => Some((Vec::new(), Vec::new())),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerLastSeen {
+    pub id: String,
+    pub online: bool,
+    /// Milliseconds since the epoch, `None` if online or not reported by the server.
+    pub last_seen: Option<i64>,
+}
+
+/// Like `query_online_states_async`, but also asks for the last-seen time of offline peers.
+/// Servers without support only answer the online bitmap, then `last_seen` is always `None`.
+pub async fn query_last_seen(ids: Vec<String>) -> Option<Vec<PeerLastSeen>> {
+    match query_online_response(&ids, true).await? {
+        Some(res) => Some(parse_last_seen(&ids, &res)),
+        None => Some(Vec::new()),
+    }
+}
+
+async fn query_online_response(
+    ids: &Vec<String>,
+    last_seen: bool,
+) -> Option<Option<OnlineResponse>> {
+    if let Err(e) = check_online_query_size(ids.len(), get_max_online_query_peers()) {
+        log::error!("{}", e);
+        return None;
+    }
+    let query_begin = Instant::now();
+    let query_timeout = std::time::Duration::from_millis(3_000);
+    loop {
+        if SHOULD_EXIT.load(Ordering::SeqCst) {
+            break;
+        }
+        match query_online_states_(ids, query_timeout, last_seen).await {
+            Ok(res) => {
+                if let Some(res) = res.as_ref() {
+                    ONLINE_CACHE.lock().unwrap().update(
+                        decode_online_bitmask(ids, &res.states),
+                        hbb_common::get_time(),
+                    );
+                }
+                return Some(res);
+            }
+            Err(e) => {
+                log::debug!("{}", &e);
+            }
+        }
+
+        if query_begin.elapsed() > query_timeout {
+            log::debug!(
+                "query onlines timeout {:?} ({:?})",
+                query_begin.elapsed(),
+                query_timeout
+            );
+            break;
+        }
+
+        sleep(1.5).await;
+    }
+    None
+}
+
+fn get_max_online_query_peers() -> usize {
+    match Config::get_option(keys::OPTION_MAX_ONLINE_QUERY_PEERS).parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => DEFAULT_MAX_ONLINE_QUERY_PEERS,
+    }
+}
+
+// An over-limit OnlineRequest may be dropped by the server, which looks like all peers offline.
+fn check_online_query_size(n: usize, max: usize) -> ResultType<()> {
+    if n > max {
+        bail!(
+            "Refuse to query the online states of {} peers at once, the maximum is {} ({})",
+            n,
+            max,
+            keys::OPTION_MAX_ONLINE_QUERY_PEERS
+        );
+    }
+    Ok(())
+}
+
+#[inline]
+fn is_online(states: &[u8], i: usize) -> bool {
+    // bytes index from left to right
+    let bit_value = 0x01 << (7 - i % 8);
+    states
+        .get(i / 8)
+        .map_or(false, |b| (b & bit_value) == bit_value)
+}
+
+/// Pairs every id of an `OnlineRequest` with its state in the `states` of the `OnlineResponse`.
+///
+/// The state of `ids[i]` is bit `7 - i % 8` of byte `i / 8`, i.e. the first id is the most
+/// significant bit of the first byte. Bits past the end of `states` are offline, the padding
+/// bits of the last byte are ignored.
+pub fn decode_online_bitmask(ids: &[String], states: &[u8]) -> Vec<(String, bool)> {
+    ids.iter()
+        .enumerate()
+        .map(|(i, id)| (id.clone(), is_online(states, i)))
+        .collect()
+}
+
+fn parse_online_states(ids: &Vec<String>, states: &[u8]) -> (Vec<String>, Vec<String>) {
+    let mut onlines = Vec::new();
+    let mut offlines = Vec::new();
+    for (id, online) in decode_online_bitmask(ids, states) {
+        if online {
+            onlines.push(id);
+        } else {
+            offlines.push(id);
+        }
+    }
+    (onlines, offlines)
+}
+
+/// The last known online state of a peer, see `export_online_cache`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedOnlineState {
+    pub id: String,
+    pub online: bool,
+    /// Milliseconds since the epoch of the query which reported it.
+    pub updated: i64,
+    /// Imported from an earlier run and not confirmed by a query since.
+    #[serde(default)]
+    pub stale: bool,
+}
+
+#[derive(Debug, Default)]
+struct OnlineCache(HashMap<String, CachedOnlineState>);
+
+impl OnlineCache {
+    fn update(&mut self, states: Vec<(String, bool)>, now: i64) {
+        for (id, online) in states {
+            self.0.insert(
+                id.clone(),
+                CachedOnlineState {
+                    id,
+                    online,
+                    updated: now,
+                    stale: false,
+                },
+            );
+        }
+    }
+
+    // Imported states never replace the ones queried in this run.
+    fn import(&mut self, states: Vec<CachedOnlineState>) {
+        for mut state in states {
+            if self.0.get(&state.id).map_or(true, |x| x.stale) {
+                state.stale = true;
+                self.0.insert(state.id.clone(), state);
+            }
+        }
+    }
+
+    fn export(&self) -> Vec<CachedOnlineState> {
+        let mut states: Vec<_> = self.0.values().cloned().collect();
+        states.sort_by(|a, b| a.id.cmp(&b.id));
+        states
+    }
+}
+
+/// The online states of all peers queried in this run or imported, to be persisted and given to
+/// `import_online_cache` on the next launch.
+pub fn export_online_cache() -> Vec<CachedOnlineState> {
+    ONLINE_CACHE.lock().unwrap().export()
+}
+
+/// Imports the states exported by an earlier run, they are marked stale until queried again.
+pub fn import_online_cache(states: Vec<CachedOnlineState>) {
+    ONLINE_CACHE.lock().unwrap().import(states);
+}
+
+/// The last known states of `ids`, to be shown before the first query completes.
+pub fn cached_online_states(ids: &[String]) -> Vec<CachedOnlineState> {
+    let cache = ONLINE_CACHE.lock().unwrap();
+    ids.iter()
+        .filter_map(|id| cache.0.get(id).cloned())
+        .collect()
+}
+
+/// The ids whose cached state is stale, to be queried first.
+pub fn stale_online_ids() -> Vec<String> {
+    let mut ids: Vec<_> = ONLINE_CACHE
+        .lock()
+        .unwrap()
+        .0
+        .values()
+        .filter(|x| x.stale)
+        .map(|x| x.id.clone())
+        .collect();
+    ids.sort();
+    ids
+}
+
+fn parse_last_seen(ids: &Vec<String>, res: &OnlineResponse) -> Vec<PeerLastSeen> {
+    // A partial list can not be matched to the ids, fall back to the bitmap.
+    let has_last_seen = res.last_seen.len() == ids.len();
+    decode_online_bitmask(ids, &res.states)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (id, online))| {
+            let last_seen = if has_last_seen && !online && res.last_seen[i] > 0 {
+                Some(res.last_seen[i])
+            } else {
+                None
+            };
+            PeerLastSeen {
+                id,
+                online,
+                last_seen,
+            }
+        })
+        .collect()
+}
+
+async fn create_online_stream() -> ResultType<FramedStream> {
+    let (rendezvous_server, _servers, _contained) =
+        crate::get_rendezvous_server(READ_TIMEOUT).await;
+    let online_server = Config::get_option(keys::OPTION_ONLINE_SERVER);
+    let online_server = if online_server.is_empty() {
+        derive_online_server(&rendezvous_server)?
+    } else {
+        check_port(online_server, RENDEZVOUS_PORT - 1)
+    };
+    let mut conn = connect_rendezvous_tcp(&rendezvous_server, &online_server).await?;
+    // Hardened servers may require the same key exchange as the main rendezvous channel.
+    if Config::get_option(keys::OPTION_ONLINE_SECURE) == "Y" {
+        let key = crate::get_key(false).await;
+        crate::secure_tcp(&mut conn, &key).await?;
+    }
+    Ok(conn)
+}
+
+// `host-1` of the rendezvous server, `online-server` is needed if it is on the first port.
+fn derive_online_server(rendezvous_server: &str) -> ResultType<String> {
+    let tmp: Vec<&str> = rendezvous_server.split(":").collect();
+    if tmp.len() != 2 {
+        bail!("Invalid server address: {}", rendezvous_server);
+    }
+    match socket_client::checked_increase_port(rendezvous_server, -1) {
+        Ok(online_server) => Ok(online_server),
+        Err(err) => bail!(
+            "No online server for {}, set online-server: {}",
+            rendezvous_server,
+            err
+        ),
+    }
+}
+
+fn online_request_auth_key(key: &str) -> auth::Key {
+    auth::Key(sha256::hash(key.as_bytes()).0)
+}
+
+fn online_request_auth_data(req: &OnlineRequest) -> Vec<u8> {
+    format!("{}\n{}\n{}", req.id, req.peers.join(","), req.timestamp).into_bytes()
+}
+
+fn sign_online_request(req: &mut OnlineRequest, key: &str) {
+    req.timestamp = hbb_common::get_time();
+    let tag = auth::authenticate(
+        &online_request_auth_data(req),
+        &online_request_auth_key(key),
+    );
+    req.signature = tag.0.to_vec().into();
+}
+
+pub fn verify_online_request(req: &OnlineRequest, key: &str) -> bool {
+    let Some(tag) = auth::Tag::from_slice(&req.signature) else {
+        return false;
+    };
+    auth::verify(
+        &tag,
+        &online_request_auth_data(req),
+        &online_request_auth_key(key),
+    )
+}
+
+// Servers that do not know the signature just ignore the extra fields.
+fn should_sign_online_request() -> bool {
+    Config::get_option(keys::OPTION_SIGN_ONLINE_REQUEST) == "Y"
+        || SERVER_CAPABILITIES
+            .lock()
+            .unwrap()
+            .values()
+            .any(|caps| caps.has(CAPABILITY_SIGNED_ONLINE_REQUEST))
+}
+
+// `Ok(None)` if the mediator is exiting.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip(ids), fields(peers = ids.len()))
+)]
+async fn query_online_states_(
+    ids: &Vec<String>,
+    timeout: std::time::Duration,
+    last_seen: bool,
+) -> ResultType<Option<OnlineResponse>> {
+    let query_begin = Instant::now();
+
+    let mut req = OnlineRequest {
+        id: Config::get_id(),
+        peers: ids.clone(),
+        last_seen,
+        ..Default::default()
+    };
+    if should_sign_online_request() {
+        sign_online_request(&mut req, &crate::get_key(false).await);
+    }
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_online_request(req);
+
+    loop {
+        if SHOULD_EXIT.load(Ordering::SeqCst) {
+            // No need to care about onlines
+            return Ok(None);
+        }
+
+        let mut socket = match create_online_stream().await {
+            Ok(s) => s,
+            Err(e) => {
+                log::debug!("Failed to create peers online stream, {e}");
+                return Ok(Some(OnlineResponse::default()));
+            }
+        };
+        if let Err(e) = socket.send(&msg_out).await {
+            log::debug!("Failed to send peers online states query, {e}");
+            return Ok(Some(OnlineResponse::default()));
+        }
+        if let Some(msg_in) = crate::common::get_next_nonkeyexchange_msg(&mut socket, None).await {
+            match msg_in.union {
+                Some(rendezvous_message::Union::OnlineResponse(online_response)) => {
+                    return Ok(Some(online_response));
+                }
+                _ => {
+                    // ignore
+                }
+            }
+        } else {
+            // TODO: Make sure socket closed?
+            bail!("Online stream receives None");
+        }
+
+        if query_begin.elapsed() > timeout {
+            bail!("Try query onlines timeout {:?}", &timeout);
+        }
+
+        sleep(300.0).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hbb_common::tokio;
+
+    #[test]
+    fn test_sign_online_request() {
+        use super::*;
+        let key = "OeVuKk5nlHiXp+APNn0Y3pC1Iwpwn44JGqrQCsWqmBw=";
+        let mut req = OnlineRequest {
+            id: "123456789".to_owned(),
+            peers: vec!["152183996".to_owned(), "165782066".to_owned()],
+            ..Default::default()
+        };
+        // unsigned request, what old clients send
+        assert!(req.signature.is_empty());
+        assert!(!verify_online_request(&req, key));
+        sign_online_request(&mut req, key);
+        assert!(req.timestamp > 0);
+        assert!(verify_online_request(&req, key));
+        assert!(!verify_online_request(&req, "another key"));
+        let mut tampered = req.clone();
+        tampered.peers.push("155323351".to_owned());
+        assert!(!verify_online_request(&tampered, key));
+        // the signed request is still a valid OnlineRequest for old servers
+        let bytes = req.write_to_bytes().unwrap();
+        let parsed = OnlineRequest::parse_from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.peers, req.peers);
+    }
+
+    #[test]
+    fn test_decode_online_bitmask() {
+        use super::*;
+        let ids: Vec<String> = (0..11).map(|i| i.to_string()).collect();
+        let decode = |states: &[u8]| -> Vec<bool> {
+            let decoded = decode_online_bitmask(&ids, states);
+            assert!(decoded.iter().zip(&ids).all(|((id, _), x)| id == x));
+            decoded.into_iter().map(|(_, online)| online).collect()
+        };
+        assert_eq!(decode(&[0xff, 0xff]), vec![true; 11]);
+        assert_eq!(decode(&[0, 0]), vec![false; 11]);
+        // padding bits of the last byte are ignored
+        assert_eq!(decode(&[0, 0x1f]), vec![false; 11]);
+        // most significant bit first, 11 ids don't fill the second byte
+        let mut expected = vec![false; 11];
+        expected[1] = true;
+        expected[7] = true;
+        expected[10] = true;
+        assert_eq!(decode(&[0x41, 0x20]), expected);
+        // missing bytes are offline
+        assert_eq!(decode(&[0xff]), [vec![true; 8], vec![false; 3]].concat());
+        assert!(decode_online_bitmask(&[], &[0xff]).is_empty());
+    }
+
+    #[test]
+    fn test_online_cache() {
+        use super::*;
+        let mut cache = OnlineCache::default();
+        cache.update(vec![("1".to_owned(), true), ("2".to_owned(), false)], 100);
+        let imported = |id: &str, online| CachedOnlineState {
+            id: id.to_owned(),
+            online,
+            updated: 50,
+            stale: false,
+        };
+        cache.import(vec![imported("1", false), imported("3", true)]);
+        let states = cache.export();
+        assert_eq!(states.len(), 3);
+        // queried in this run, kept
+        assert_eq!(states[0].id, "1");
+        assert!(states[0].online && !states[0].stale);
+        assert_eq!(states[2].id, "3");
+        assert!(states[2].online && states[2].stale);
+        // a query confirms a stale state
+        cache.update(vec![("3".to_owned(), false)], 200);
+        assert!(cache.0.values().all(|x| !x.stale));
+        assert_eq!(cache.0["3"].updated, 200);
+        let json = serde_json::to_string(&cache.export()).unwrap();
+        let states: Vec<CachedOnlineState> = serde_json::from_str(&json).unwrap();
+        assert_eq!(states, cache.export());
+    }
+
+    #[test]
+    fn test_parse_last_seen() {
+        use super::*;
+        let ids: Vec<String> = (0..9).map(|i| i.to_string()).collect();
+        // 0 and 8 online
+        let states = vec![0x80, 0x80];
+        assert_eq!(
+            parse_online_states(&ids, &states),
+            (
+                vec!["0".to_owned(), "8".to_owned()],
+                (1..8).map(|i| i.to_string()).collect()
+            )
+        );
+        let mut res = OnlineResponse {
+            states: states.into(),
+            ..Default::default()
+        };
+        let peers = parse_last_seen(&ids, &res);
+        assert!(peers.iter().all(|p| p.last_seen.is_none()));
+        assert!(peers[0].online && !peers[1].online && peers[8].online);
+        res.last_seen = vec![0, 1000, 0, 3000, 0, 0, 0, 0, 0];
+        let peers = parse_last_seen(&ids, &res);
+        assert_eq!(peers[1].last_seen, Some(1000));
+        assert_eq!(peers[2].last_seen, None);
+        assert_eq!(peers[3].last_seen, Some(3000));
+        // short bitmap, the rest is offline
+        let peers = parse_last_seen(&ids, &OnlineResponse::default());
+        assert!(peers.iter().all(|p| !p.online && p.last_seen.is_none()));
+    }
+
+    #[test]
+    fn test_online_query_size() {
+        use super::*;
+        assert!(check_online_query_size(0, DEFAULT_MAX_ONLINE_QUERY_PEERS).is_ok());
+        assert!(check_online_query_size(
+            DEFAULT_MAX_ONLINE_QUERY_PEERS,
+            DEFAULT_MAX_ONLINE_QUERY_PEERS
+        )
+        .is_ok());
+        let err = check_online_query_size(
+            DEFAULT_MAX_ONLINE_QUERY_PEERS + 1,
+            DEFAULT_MAX_ONLINE_QUERY_PEERS,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("10001 peers"));
+        assert!(check_online_query_size(3, 2).is_err());
+    }
+
+    #[test]
+    fn test_online_poller() {
+        use super::*;
+        let ids = |v: &[&str]| v.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let mut poller = OnlinePoller::default();
+        // never seen online
+        assert_eq!(
+            poller.debounce(2, ids(&["a"]), ids(&["b"])),
+            (ids(&["a"]), ids(&["b"]))
+        );
+        assert_eq!(
+            poller.debounce(2, ids(&["b"]), ids(&["a"])),
+            (ids(&["b", "a"]), ids(&[]))
+        );
+        assert_eq!(
+            poller.debounce(2, ids(&[]), ids(&["a", "b"])),
+            (ids(&["b"]), ids(&["a"]))
+        );
+        assert_eq!(
+            poller.debounce(2, ids(&[]), ids(&["a", "b"])),
+            (ids(&[]), ids(&["a", "b"]))
+        );
+        // a grace of 1 is the old behavior
+        assert_eq!(
+            poller.debounce(1, ids(&["a"]), ids(&[])),
+            (ids(&["a"]), ids(&[]))
+        );
+        assert_eq!(
+            poller.debounce(1, ids(&[]), ids(&["a"])),
+            (ids(&[]), ids(&["a"]))
+        );
+    }
+
+    #[test]
+    fn test_derive_online_server() {
+        use super::*;
+        assert_eq!(
+            derive_online_server("rs.example.com:21116").unwrap(),
+            "rs.example.com:21115"
+        );
+        assert_eq!(
+            derive_online_server("rs.example.com:65535").unwrap(),
+            "rs.example.com:65534"
+        );
+        assert!(derive_online_server("rs.example.com:1").is_err());
+        assert!(derive_online_server("rs.example.com:0").is_err());
+        assert!(derive_online_server("rs.example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_onlines_async() {
+        let ids = vec!["152183996".to_owned(), "165782066".to_owned()];
+        if let Some((onlines, offlines)) = super::query_online_states_async(ids.clone()).await {
+            assert_eq!(onlines.len() + offlines.len(), ids.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_onlines() {
+        super::query_online_states(
+            vec![
+                "152183996".to_owned(),
+                "165782066".to_owned(),
+                "155323351".to_owned(),
+                "460952777".to_owned(),
+            ],
+            |onlines: Vec<String>, offlines: Vec<String>| {
+                println!("onlines: {:?}, offlines: {:?}", &onlines, &offlines);
+            },
+        )
+        .await;
+    }
+}
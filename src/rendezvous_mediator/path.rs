@@ -0,0 +1,251 @@
+use super::*;
+
+fn is_peer_listed(list: &str, id: &str) -> bool {
+    !id.is_empty() && list.split(',').any(|x| x.trim() == id)
+}
+
+// The id of the peer asking to connect is only known if the rendezvous server forwards it in
+// `requester_id` of `RequestRelay`, `PunchHole` or `FetchLocalAddr`. `RequestRelay.id` is our own
+// id, and older servers leave `requester_id` empty, in which case the connection is not refused.
+pub(super) fn check_relay_allowed(requester_id: &str) -> ResultType<()> {
+    if is_peer_listed(
+        &Config::get_option(keys::OPTION_NO_RELAY_PEERS),
+        requester_id,
+    ) {
+        bail!(
+            "Refuse to relay the connection from {}, it is in {}",
+            requester_id,
+            keys::OPTION_NO_RELAY_PEERS
+        );
+    }
+    Ok(())
+}
+
+/// A connection path forced for testing, see `set_forced_connection_path`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForcedPath {
+    /// Connect over the lan, as for an intranet request.
+    Direct,
+    PunchHole,
+    Relay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum PathStep {
+    Relay,
+    Intranet,
+    PunchHole,
+}
+
+/// Make every incoming punch hole and intranet request take `path` regardless of the NAT types
+/// and relay settings, without falling back to another path if it fails, `None` to stop.
+/// For testing only, it is kept in memory and cleared on restart.
+pub fn set_forced_connection_path(path: Option<ForcedPath>) {
+    log::warn!("Forced connection path: {:?}", path);
+    *FORCED_PATH.write().unwrap() = path;
+}
+
+pub(super) fn forced_connection_path() -> Option<ForcedPath> {
+    let path = *FORCED_PATH.read().unwrap();
+    if let Some(path) = path {
+        log::warn!(
+            "Forced connection path {:?} is active, for testing only",
+            path
+        );
+    }
+    path
+}
+
+// The first path of a punch hole request, it falls back to punching the hole and then to relay
+// unless it is forced.
+pub(super) fn punch_hole_path(
+    forced: Option<ForcedPath>,
+    relay: bool,
+    lan_preferred: bool,
+) -> PathStep {
+    match forced {
+        Some(ForcedPath::Direct) => PathStep::Intranet,
+        Some(ForcedPath::PunchHole) => PathStep::PunchHole,
+        Some(ForcedPath::Relay) => PathStep::Relay,
+        None if relay => PathStep::Relay,
+        None if lan_preferred => PathStep::Intranet,
+        None => PathStep::PunchHole,
+    }
+}
+
+// An intranet request can not punch a hole, it is answered directly unless relayed.
+pub(super) fn intranet_path(forced: Option<ForcedPath>, direct_allowed: bool) -> PathStep {
+    match forced {
+        Some(ForcedPath::Relay) => PathStep::Relay,
+        Some(_) => PathStep::Intranet,
+        None if direct_allowed => PathStep::Intranet,
+        None => PathStep::Relay,
+    }
+}
+
+// `force-relay-peers` is a comma separated list of `id` or `id=Y` to always relay the connections
+// from `id`, or `id=N` to not relay them just because of the global `force-relay`.
+fn peer_force_relay(list: &str, id: &str) -> Option<bool> {
+    if id.is_empty() {
+        return None;
+    }
+    list.split(',').find_map(|x| {
+        let (peer, v) = x.split_once('=').unwrap_or((x, "Y"));
+        (peer.trim() == id).then(|| v.trim() != "N")
+    })
+}
+
+// The per-peer setting wins over the global `force-relay`, which wins over the NAT types. A punch
+// hole which is not forced to relay still falls back to relay if it fails.
+fn should_force_relay(per_peer: Option<bool>, global: bool, nat_based: bool) -> bool {
+    per_peer.unwrap_or(global || nat_based)
+}
+
+pub(super) fn is_force_relay(requester_id: &str, nat_based: bool) -> bool {
+    should_force_relay(
+        peer_force_relay(
+            &Config::get_option(keys::OPTION_FORCE_RELAY_PEERS),
+            requester_id,
+        ),
+        Config::get_option(keys::OPTION_FORCE_RELAY) == "Y",
+        nat_based,
+    )
+}
+
+/// Always relay the connections from `id` if `force` is `Some(true)`, never force relay for it if
+/// `Some(false)`, or remove it from `force-relay-peers` if `None`.
+pub fn set_peer_force_relay(id: &str, force: Option<bool>) {
+    let list = Config::get_option(keys::OPTION_FORCE_RELAY_PEERS);
+    let mut peers: Vec<String> = list
+        .split(',')
+        .filter(|x| {
+            let peer = x.split_once('=').map_or(*x, |(peer, _)| peer).trim();
+            !peer.is_empty() && peer != id
+        })
+        .map(|x| x.trim().to_owned())
+        .collect();
+    match force {
+        Some(true) => peers.push(id.to_owned()),
+        Some(false) => peers.push(format!("{}=N", id)),
+        None => {}
+    }
+    Config::set_option(keys::OPTION_FORCE_RELAY_PEERS.to_owned(), peers.join(","));
+}
+
+// `relay-allowlist` is a comma separated list of relay servers, the default relay port is assumed
+// if omitted. If it is set, the `relay-server` option and the relay server provided by the
+// rendezvous server have to be on the list, the first entry replaces a `host+1` fallback not on it.
+pub(super) fn check_relay_server(allowlist: &str, relay_server: &str) -> ResultType<()> {
+    if !is_relay_allowed(allowlist, relay_server) {
+        log::warn!(
+            "Relay server {} is not in {}, refuse to relay",
+            relay_server,
+            keys::OPTION_RELAY_ALLOWLIST
+        );
+        bail!("Relay server {} is not allowed", relay_server);
+    }
+    Ok(())
+}
+
+pub(super) fn is_relay_allowed(allowlist: &str, relay_server: &str) -> bool {
+    let allowlist = allowlist.trim();
+    if allowlist.is_empty() {
+        return true;
+    }
+    let normalize = |x: &str| check_port(x.trim(), config::RELAY_PORT).to_lowercase();
+    let relay = normalize(relay_server);
+    allowlist
+        .split(',')
+        .filter(|x| !x.trim().is_empty())
+        .any(|x| normalize(x) == relay)
+}
+
+// With `prefer-lan`, a requester found on the local subnet by the lan discovery is answered
+// with the local address instead of punching a hole.
+pub(super) fn is_lan_preferred(requester_id: &str) -> bool {
+    if requester_id.is_empty() || Config::get_option(keys::OPTION_PREFER_LAN) != "Y" {
+        return false;
+    }
+    #[cfg(not(target_os = "ios"))]
+    return crate::lan::is_lan_peer(requester_id);
+    #[cfg(target_os = "ios")]
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_forced_connection_path() {
+        use super::*;
+        use ForcedPath::*;
+        assert_eq!(punch_hole_path(None, false, false), PathStep::PunchHole);
+        assert_eq!(punch_hole_path(None, false, true), PathStep::Intranet);
+        assert_eq!(punch_hole_path(None, true, true), PathStep::Relay);
+        // regardless of force-relay and the NAT types
+        for relay in [false, true] {
+            for lan in [false, true] {
+                assert_eq!(
+                    punch_hole_path(Some(Direct), relay, lan),
+                    PathStep::Intranet
+                );
+                assert_eq!(
+                    punch_hole_path(Some(PunchHole), relay, lan),
+                    PathStep::PunchHole
+                );
+                assert_eq!(punch_hole_path(Some(Relay), relay, lan), PathStep::Relay);
+            }
+        }
+        assert_eq!(intranet_path(None, true), PathStep::Intranet);
+        assert_eq!(intranet_path(None, false), PathStep::Relay);
+        assert_eq!(intranet_path(Some(Direct), false), PathStep::Intranet);
+        assert_eq!(intranet_path(Some(PunchHole), false), PathStep::Intranet);
+        assert_eq!(intranet_path(Some(Relay), true), PathStep::Relay);
+    }
+
+    #[test]
+    fn test_force_relay_peers() {
+        use super::*;
+        let list = "111, 222=N,333=Y";
+        assert_eq!(peer_force_relay(list, "111"), Some(true));
+        assert_eq!(peer_force_relay(list, "222"), Some(false));
+        assert_eq!(peer_force_relay(list, "333"), Some(true));
+        assert_eq!(peer_force_relay(list, "444"), None);
+        // older servers do not forward the requester id
+        assert_eq!(peer_force_relay(list, ""), None);
+        for global in [false, true] {
+            for nat_based in [false, true] {
+                assert!(should_force_relay(Some(true), global, nat_based));
+                assert!(!should_force_relay(Some(false), global, nat_based));
+                assert_eq!(
+                    should_force_relay(None, global, nat_based),
+                    global || nat_based
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_relay_peers() {
+        use super::*;
+        let list = "123456789, 987654321";
+        assert!(is_peer_listed(list, "123456789"));
+        assert!(is_peer_listed(list, "987654321"));
+        assert!(!is_peer_listed(list, "12345678"));
+        assert!(!is_peer_listed(list, ""));
+        assert!(!is_peer_listed("", ""));
+    }
+
+    #[test]
+    fn test_relay_allowlist() {
+        use super::*;
+        let allowlist = "relay.example.com, 10.0.0.1:21119";
+        assert!(check_relay_server(allowlist, "relay.example.com").is_ok());
+        let with_port = format!("RELAY.example.com:{}", config::RELAY_PORT);
+        assert!(check_relay_server(allowlist, &with_port).is_ok());
+        assert!(check_relay_server(allowlist, "10.0.0.1:21119").is_ok());
+        // provided by the rendezvous server but not on the list
+        assert!(check_relay_server(allowlist, "evil.example.com").is_err());
+        assert!(check_relay_server(allowlist, "10.0.0.1").is_err());
+        assert!(check_relay_server("", "evil.example.com").is_ok());
+    }
+}
@@ -0,0 +1,134 @@
+use super::*;
+
+// How often the servers are probed again if only the fastest one is used.
+const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+// How long `probe_transports` waits for each transport.
+const TRANSPORT_PROBE_TIMEOUT: u64 = 5_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TransportReachability {
+    pub host: String,
+    // The round trip in ms, `None` if the transport is unreachable.
+    pub udp: Option<u64>,
+    pub tcp: Option<u64>,
+}
+
+/// Probe udp and tcp to the rendezvous server `host` at once, e.g. to tell the user that udp is
+/// blocked on the network. The probes use their own sockets and leave the config untouched.
+pub async fn probe_transports(host: &str) -> TransportReachability {
+    let host = check_port(host, RENDEZVOUS_PORT);
+    let (udp, tcp) = tokio::join!(probe_udp(&host), probe_tcp(&host));
+    TransportReachability { host, udp, tcp }
+}
+
+// An id nobody registers, the server only asks for its key and the registration of this device
+// stays on the live socket.
+fn probe_peer_id() -> String {
+    format!("probe-{}", Uuid::new_v4().simple())
+}
+
+// A RegisterPeer answered by the server.
+async fn probe_udp(host: &str) -> Option<u64> {
+    let start = Instant::now();
+    let res = hbb_common::timeout(TRANSPORT_PROBE_TIMEOUT, async {
+        let target = rendezvous_udp_target(host, rendezvous_proxy(host).is_some(), false);
+        let (mut socket, addr) =
+            socket_client::new_udp_for_via(&target, rendezvous_proxy(host), CONNECT_TIMEOUT)
+                .await
+                .ok()?;
+        let mut msg_out = Message::new();
+        msg_out.set_register_peer(RegisterPeer {
+            id: probe_peer_id(),
+            ..Default::default()
+        });
+        socket.send(&msg_out, addr).await.ok()?;
+        match socket.next().await {
+            Some(Ok(_)) => Some(()),
+            _ => None,
+        }
+    })
+    .await;
+    match res {
+        Ok(Some(())) => Some(start.elapsed().as_millis() as _),
+        _ => None,
+    }
+}
+
+// A tcp connection and the key exchange.
+async fn probe_tcp(host: &str) -> Option<u64> {
+    let start = Instant::now();
+    match hbb_common::timeout(
+        TRANSPORT_PROBE_TIMEOUT,
+        RendezvousMediator::connect_secure_tcp(host),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Some(start.elapsed().as_millis() as _),
+        _ => None,
+    }
+}
+
+pub(super) async fn probe_fastest_server(servers: &[String]) -> Option<String> {
+    let latencies = join_all(
+        servers
+            .iter()
+            .map(|host| async move { (host.clone(), crate::probe_rendezvous_server(host).await) }),
+    )
+    .await;
+    log::debug!("Latencies of rendezvous servers: {:?}", latencies);
+    select_fastest_server(latencies)
+}
+
+// Restart the mediator if another server becomes the fastest one.
+pub(super) async fn reprobe_fastest_server(current: String) {
+    loop {
+        let begin = Instant::now();
+        while begin.elapsed() < REPROBE_INTERVAL {
+            if SHOULD_EXIT.load(Ordering::SeqCst) {
+                return;
+            }
+            sleep(1.).await;
+        }
+        let servers = Config::get_rendezvous_servers();
+        match probe_fastest_server(&servers).await {
+            Some(fastest) if fastest != current => {
+                log::info!(
+                    "Rendezvous server {} is faster than {}, switch to it",
+                    fastest,
+                    current
+                );
+                RendezvousMediator::restart();
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hbb_common::tokio;
+
+    #[tokio::test]
+    async fn test_probe_transports() {
+        use super::*;
+        let mut responder = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let port = responder.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            if let Some(Ok((bytes, addr))) = responder.next().await {
+                // not this device, its registration is left alone
+                let msg_in = Message::parse_from_bytes(&bytes).unwrap();
+                let id = msg_in.register_peer().id.clone();
+                assert!(id.starts_with("probe-") && id != Config::get_id());
+                let mut msg_out = Message::new();
+                msg_out.set_register_peer_response(RegisterPeerResponse::default());
+                responder.send(&msg_out, addr).await.ok();
+            }
+        });
+        // nothing listens on the tcp port
+        let res = probe_transports(&format!("127.0.0.1:{}", port)).await;
+        assert_eq!(res.host, format!("127.0.0.1:{}", port));
+        assert!(res.udp.is_some());
+        assert_eq!(res.tcp, None);
+    }
+}
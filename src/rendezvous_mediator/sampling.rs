@@ -0,0 +1,150 @@
+use super::*;
+
+lazy_static::lazy_static! {
+    static ref LOG_SAMPLES: std::sync::Mutex<HashMap<String, SampledLog>> = Default::default();
+}
+
+#[inline]
+pub(super) fn is_debug_proto() -> bool {
+    Config::get_option(keys::OPTION_RENDEZVOUS_DEBUG_PROTO) == "Y"
+}
+
+// The variant name only, the message itself may carry keys and addresses.
+pub(super) fn message_name(msg: &Option<rendezvous_message::Union>) -> String {
+    match msg {
+        Some(msg) => format!("{:?}", msg)
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_owned(),
+        None => "<empty>".to_owned(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogSampling {
+    // the first and then every nth event
+    Every(u64),
+    // the first event of every interval
+    Interval(Duration),
+}
+
+// `rendezvous-log-sampling` for the messages repeated on every reconnect, e.g. "10" for every
+// 10th one, "60s" for at most one per minute. Empty or invalid to log all of them.
+fn get_log_sampling() -> Option<LogSampling> {
+    parse_log_sampling(&Config::get_option(keys::OPTION_RENDEZVOUS_LOG_SAMPLING))
+}
+
+fn parse_log_sampling(s: &str) -> Option<LogSampling> {
+    let s = s.trim();
+    let sampling = match s.strip_suffix('s') {
+        Some(secs) => LogSampling::Interval(Duration::from_secs(secs.trim().parse().ok()?)),
+        None => LogSampling::Every(s.parse().ok()?),
+    };
+    match sampling {
+        LogSampling::Every(0 | 1) => None,
+        LogSampling::Interval(d) if d.is_zero() => None,
+        _ => Some(sampling),
+    }
+}
+
+// Logs a sample of a repeated event and counts the ones in between.
+pub(super) struct SampledLog {
+    sampling: LogSampling,
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl SampledLog {
+    pub(super) fn new(interval: Duration) -> Self {
+        Self::with(LogSampling::Interval(interval))
+    }
+
+    fn with(sampling: LogSampling) -> Self {
+        Self {
+            sampling,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    // The number of events suppressed since the last log if this one should be logged.
+    pub(super) fn allow(&mut self, now: Instant) -> Option<u64> {
+        let allow = match (self.sampling, self.last) {
+            (_, None) => true,
+            (LogSampling::Every(n), _) => self.suppressed + 1 >= n,
+            (LogSampling::Interval(interval), Some(last)) => {
+                now.saturating_duration_since(last) >= interval
+            }
+        };
+        if !allow {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+// Whether a repeated message of `key` is logged under `rendezvous-log-sampling`, with the number
+// of them suppressed since. Errors and state changes are never sampled.
+pub(super) fn sample_log(key: &str) -> Option<u64> {
+    let Some(sampling) = get_log_sampling() else {
+        return Some(0);
+    };
+    let mut samples = LOG_SAMPLES.lock().unwrap();
+    let log = samples
+        .entry(key.to_owned())
+        .or_insert_with(|| SampledLog::with(sampling));
+    if log.sampling != sampling {
+        *log = SampledLog::with(sampling);
+    }
+    log.allow(Instant::now())
+}
+
+pub(super) fn describe_suppressed(suppressed: u64) -> String {
+    if suppressed == 0 {
+        "".to_owned()
+    } else {
+        format!(" ({} similar messages suppressed)", suppressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_ignored_message_log() {
+        use super::*;
+        let mut msg = Message::new();
+        msg.set_register_peer_response(RegisterPeerResponse {
+            request_pk: true,
+            ..Default::default()
+        });
+        assert_eq!(message_name(&msg.union), "RegisterPeerResponse");
+        assert_eq!(message_name(&None), "<empty>");
+
+        let start = Instant::now();
+        let mut log = SampledLog::new(Duration::from_secs(60));
+        assert_eq!(log.allow(start), Some(0));
+        assert_eq!(log.allow(start + Duration::from_secs(1)), None);
+        assert_eq!(log.allow(start + Duration::from_secs(59)), None);
+        assert_eq!(log.allow(start + Duration::from_secs(60)), Some(2));
+        assert_eq!(log.allow(start + Duration::from_secs(61)), None);
+
+        assert_eq!(parse_log_sampling(""), None);
+        assert_eq!(parse_log_sampling("1"), None);
+        assert_eq!(parse_log_sampling("0s"), None);
+        assert_eq!(parse_log_sampling("x"), None);
+        assert_eq!(parse_log_sampling("10"), Some(LogSampling::Every(10)));
+        assert_eq!(
+            parse_log_sampling("60s"),
+            Some(LogSampling::Interval(Duration::from_secs(60)))
+        );
+        let mut log = SampledLog::with(LogSampling::Every(3));
+        let logged: Vec<_> = (0..7).map(|_| log.allow(start)).collect();
+        assert_eq!(
+            logged,
+            vec![Some(0), None, None, Some(2), None, None, Some(2)]
+        );
+    }
+}
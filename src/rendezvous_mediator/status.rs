@@ -0,0 +1,177 @@
+use super::*;
+
+// Only the proxy address is shown, the credentials are left out.
+fn describe_proxy(proxy: &Option<Socks5Server>) -> String {
+    match proxy {
+        None => "direct".to_owned(),
+        Some(conf) if conf.username.is_empty() => conf.proxy.clone(),
+        Some(conf) => format!("{} (with credentials)", conf.proxy),
+    }
+}
+
+fn describe_latency(latency: Option<i64>) -> String {
+    match latency {
+        None | Some(0) => "unknown".to_owned(),
+        Some(x) if x < 0 => "unreachable".to_owned(),
+        Some(x) => format!("{}ms", x as f64 / 1000.),
+    }
+}
+
+/// A summary of the rendezvous and capture state to paste into an issue.
+/// No key material is included.
+pub fn diagnostics_snapshot() -> String {
+    use hbb_common::protobuf::Enum;
+    let mut lines = vec![format!("version: {}", crate::VERSION)];
+    let mut active: Vec<_> = ACTIVE_RENDEZVOUS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(host, transport)| (host.clone(), *transport))
+        .collect();
+    active.sort_by(|a, b| a.0.cmp(&b.0));
+    if active.is_empty() {
+        lines.push(format!(
+            "rendezvous: not connected, lan only: {}, no server configured: {}",
+            is_lan_only(),
+            is_no_server_configured()
+        ));
+    }
+    for (host, transport) in active {
+        lines.push(format!(
+            "rendezvous: {} over {:?}, latency: {}, fails: {}, capabilities: {:#x}, version: {:?}, proxy: {}",
+            host,
+            transport,
+            describe_latency(Config::get_latency(&host)),
+            get_transport_failures(&host, transport),
+            server_capabilities(&host),
+            get_server_capabilities(&host).version,
+            describe_proxy(&rendezvous_proxy(&host)),
+        ));
+    }
+    lines.push(format!(
+        "keep_alive: {:?}, heartbeat misses: {}, key confirmed: {}",
+        current_keep_alive(),
+        tcp_heartbeat_misses(),
+        Config::get_key_confirmed(),
+    ));
+    lines.push(format!(
+        "nat type: {:?}, tested by: {}",
+        NatType::from_i32(Config::get_nat_type()).unwrap_or(NatType::UNKNOWN_NAT),
+        crate::common::nat_test_server()
+    ));
+    if let Some(path) = *FORCED_PATH.read().unwrap() {
+        lines.push(format!(
+            "forced connection path: {:?}, for testing only",
+            path
+        ));
+    }
+    let paths = get_connection_paths();
+    let count_paths = |p| paths.iter().filter(|(_, x)| *x == p).count();
+    let tasks = active_tasks();
+    let count_tasks = |k| tasks.iter().filter(|x| x.kind == k).count();
+    lines.push(format!(
+        "connections: direct {}, relay {}; tasks: punch hole {}, relay {}, intranet {}",
+        count_paths(ConnectionPath::Direct),
+        count_paths(ConnectionPath::Relay),
+        count_tasks(TaskKind::PunchHole),
+        count_tasks(TaskKind::Relay),
+        count_tasks(TaskKind::Intranet),
+    ));
+    lines.push(format!(
+        "relay: {} bytes, {} bytes/s",
+        crate::server::relay_bytes(),
+        crate::server::relay_throughput()
+    ));
+    lines.push(format!("proxy: {}", describe_proxy(&Config::get_socks())));
+    #[cfg(target_os = "linux")]
+    if !crate::platform::linux::is_x11() {
+        match crate::server::wayland::list_displays() {
+            Ok(displays) => {
+                let active = crate::server::wayland::active_display();
+                for (i, d) in displays.iter().enumerate() {
+                    lines.push(format!(
+                        "wayland display {}{}: {}x{}",
+                        i,
+                        if active == Some(i) { " (active)" } else { "" },
+                        d.width,
+                        d.height
+                    ));
+                }
+                if let Some((display, dmabuf, path)) = crate::server::wayland::capture_path() {
+                    lines.push(format!(
+                        "wayland display {} capture path: {:?}, dma-buf offered: {}",
+                        display, path, dmabuf
+                    ));
+                }
+                let blanks = crate::server::wayland::blank_rects();
+                if !blanks.is_empty() {
+                    lines.push(format!("wayland blanked regions: {:?}", blanks));
+                }
+            }
+            Err(_) => lines.push("wayland: not initialized".to_owned()),
+        }
+        if let Some(latency) = scrap::wayland::pipewire::frame_pacing_latency() {
+            lines.push(format!("wayland frame pacing latency: {:?}", latency));
+        }
+        if scrap::wayland::pipewire::is_damage_tracking() {
+            lines.push(format!(
+                "wayland damage tracking, unchanged bytes: {}",
+                scrap::wayland::pipewire::damage_bytes_saved()
+            ));
+        }
+        let (pooled, allocations) = scrap::buffer_pool::pool_stats();
+        lines.push(format!(
+            "frame buffer pool: {} bytes pooled, {} allocations",
+            pooled, allocations
+        ));
+    }
+    lines.join("\n")
+}
+
+pub fn tcp_heartbeat_misses() -> u32 {
+    TCP_HEARTBEAT_MISSES.load(Ordering::SeqCst)
+}
+
+pub fn current_keep_alive() -> Option<i32> {
+    let keep_alive = NEGOTIATED_KEEP_ALIVE.load(Ordering::SeqCst);
+    if keep_alive > 0 {
+        Some(keep_alive)
+    } else {
+        None
+    }
+}
+
+/// Bitrate cap in kbps for the encoder, 0 means no cap.
+/// Relay bandwidth is shared, so the relay cap wins if any connection is relayed.
+pub fn suggested_bitrate_cap() -> u32 {
+    let relayed = CONNECTION_PATHS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|path| *path == ConnectionPath::Relay);
+    let key = if relayed {
+        keys::OPTION_RELAY_BITRATE_CAP
+    } else {
+        keys::OPTION_DIRECT_BITRATE_CAP
+    };
+    Config::get_option(key).parse().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_describe_proxy() {
+        use super::*;
+        assert_eq!(describe_proxy(&None), "direct");
+        let proxy = Some(Socks5Server {
+            proxy: "socks5://127.0.0.1:1080".to_owned(),
+            username: "user".to_owned(),
+            password: "secret".to_owned(),
+        });
+        let s = describe_proxy(&proxy);
+        assert!(s.starts_with("socks5://127.0.0.1:1080"));
+        assert!(!s.contains("user") && !s.contains("secret"));
+        assert_eq!(describe_latency(Some(-1)), "unreachable");
+        assert_eq!(describe_latency(Some(12_500)), "12.5ms");
+    }
+}
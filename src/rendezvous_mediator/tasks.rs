@@ -0,0 +1,140 @@
+use super::*;
+
+// Punch hole, relay and intranet tasks running at the same time, new requests are dropped beyond it.
+const MAX_RENDEZVOUS_TASKS: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref TASKS: std::sync::Mutex<TaskRegistry> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    PunchHole,
+    Relay,
+    Intranet,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub kind: TaskKind,
+    // The relay uuid, empty if not known when the task starts.
+    pub uuid: String,
+    pub start: Instant,
+}
+
+// The tasks spawned for the requests of the rendezvous server.
+#[derive(Default)]
+struct TaskRegistry {
+    set: JoinSet<()>,
+    tasks: HashMap<u64, (TaskInfo, AbortHandle)>,
+    next_id: u64,
+}
+
+impl TaskRegistry {
+    fn reap(&mut self) {
+        while let Some(res) = self.set.try_join_next() {
+            if let Err(err) = res {
+                if err.is_panic() {
+                    log::error!("rendezvous task panicked: {}", err);
+                }
+            }
+        }
+    }
+}
+
+// Removes the task from the registry however it ends, also if it is aborted or panics.
+struct TaskGuard(u64);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        TASKS.lock().unwrap().tasks.remove(&self.0);
+    }
+}
+
+pub(super) fn spawn_task<F>(kind: TaskKind, uuid: String, fut: F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    let mut lock = TASKS.lock().unwrap();
+    lock.reap();
+    if lock.tasks.len() >= MAX_RENDEZVOUS_TASKS {
+        log::warn!(
+            "Too many rendezvous tasks, drop {:?} request {}",
+            kind,
+            uuid
+        );
+        return;
+    }
+    let id = lock.next_id;
+    lock.next_id += 1;
+    let guard = TaskGuard(id);
+    let handle = lock.set.spawn(async move {
+        let _guard = guard;
+        fut.await;
+    });
+    let info = TaskInfo {
+        kind,
+        uuid,
+        start: Instant::now(),
+    };
+    lock.tasks.insert(id, (info, handle));
+}
+
+pub fn active_tasks() -> Vec<TaskInfo> {
+    let mut lock = TASKS.lock().unwrap();
+    lock.reap();
+    lock.tasks.values().map(|(info, _)| info.clone()).collect()
+}
+
+/// Abort the tasks of relay `uuid`, returns how many are aborted.
+pub fn abort_tasks(uuid: &str) -> usize {
+    let lock = TASKS.lock().unwrap();
+    let mut n = 0;
+    for (info, handle) in lock.tasks.values() {
+        if info.uuid == uuid {
+            handle.abort();
+            n += 1;
+        }
+    }
+    n
+}
+
+pub fn abort_all_tasks() {
+    TASKS.lock().unwrap().set.abort_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use hbb_common::tokio;
+
+    #[tokio::test]
+    async fn test_task_registry() {
+        use super::*;
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        spawn_task(TaskKind::Relay, "task.test.1".to_owned(), async move {
+            rx.await.ok();
+        });
+        spawn_task(TaskKind::Relay, "task.test.2".to_owned(), async move {
+            sleep(60.).await;
+        });
+        let uuids = |tasks: Vec<TaskInfo>| -> Vec<String> {
+            let mut v: Vec<String> = tasks
+                .into_iter()
+                .filter(|t| t.uuid.starts_with("task.test."))
+                .map(|t| t.uuid)
+                .collect();
+            v.sort();
+            v
+        };
+        assert_eq!(uuids(active_tasks()), vec!["task.test.1", "task.test.2"]);
+        tx.send(()).ok();
+        assert_eq!(abort_tasks("task.test.2"), 1);
+        for _ in 0..100 {
+            if uuids(active_tasks()).is_empty() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(uuids(active_tasks()).is_empty());
+    }
+}
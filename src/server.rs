@@ -1,8 +1,11 @@
 use std::{
     collections::HashMap,
     net::SocketAddr,
-    sync::{Arc, Mutex, RwLock, Weak},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
@@ -10,18 +13,19 @@ use bytes::Bytes;
 pub use connection::*;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use hbb_common::config::Config2;
-use hbb_common::tcp::{self, new_listener};
+use hbb_common::tcp::{self, new_listener_with_tos, ConnectOptions};
 use hbb_common::{
     allow_err,
     anyhow::Context,
     bail,
-    config::{Config, CONNECT_TIMEOUT, RELAY_PORT},
+    config::{keys, Config, CONNECT_TIMEOUT, RELAY_PORT},
     log,
     message_proto::*,
     protobuf::{Enum, Message as _},
     rendezvous_proto::*,
     socket_client,
     sodiumoxide::crypto::{box_, sign},
+    throttle::{SharedBucket, ThrottledStream, TokenBucket},
     timeout, tokio, ResultType, Stream,
 };
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -68,6 +72,8 @@ type ConnMap = HashMap<i32, ConnInner>;
 
 #[cfg(any(target_os = "macos", target_os = "linux"))]
 const CONFIG_SYNC_INTERVAL_SECS: f32 = 0.3;
+// A warm relay connection is replaced before the relay server drops it for being idle.
+const WARM_RELAY_TTL: Duration = Duration::from_secs(20);
 
 lazy_static::lazy_static! {
     pub static ref CHILD_PROCESS: Childs = Default::default();
@@ -80,6 +86,120 @@ lazy_static::lazy_static! {
     // Now we use this [`CLIENT_SERVER`] to do following operations:
     // - record local audio, and send to remote
     pub static ref CLIENT_SERVER: ServerPtr = new();
+    // A connection to the relay server opened ahead of a relay request, see `relay-prewarm`.
+    static ref WARM_RELAY: Mutex<Option<WarmRelay>> = Default::default();
+    // The bucket all relay connections share with `relay-rate-limit-scope` set to "global".
+    static ref GLOBAL_RELAY_BUCKET: Mutex<Option<SharedBucket>> = Default::default();
+    static ref RELAY_BYTES: Arc<AtomicU64> = Default::default();
+    // When the throughput was sampled, the bytes then and the bytes per second since the sample before.
+    static ref RELAY_THROUGHPUT: Mutex<(Instant, u64, u64)> = Mutex::new((Instant::now(), 0, 0));
+}
+
+struct WarmRelay {
+    target: String,
+    stream: Stream,
+    created: Instant,
+}
+
+#[inline]
+fn relay_target(relay_server: String, ipv4: bool) -> String {
+    socket_client::ipv4_to_ipv6(crate::check_port(relay_server, RELAY_PORT), ipv4)
+}
+
+/// Whether a warm connection to `relay_server` is ready for the next relay request.
+pub fn is_warm_relay_ready(relay_server: &str, ipv4: bool) -> bool {
+    let target = relay_target(relay_server.to_owned(), ipv4);
+    WARM_RELAY.lock().unwrap().as_ref().map_or(false, |w| {
+        w.target == target && w.created.elapsed() < WARM_RELAY_TTL
+    })
+}
+
+// Refreshed halfway through its lifetime, so one is always ready.
+pub fn warm_relay_needs_refresh(relay_server: &str, ipv4: bool) -> bool {
+    let target = relay_target(relay_server.to_owned(), ipv4);
+    WARM_RELAY.lock().unwrap().as_ref().map_or(true, |w| {
+        w.target != target || w.created.elapsed() >= WARM_RELAY_TTL / 2
+    })
+}
+
+/// Open a connection to `relay_server` which the next relay request uses instead of connecting.
+/// It replaces the previous one, call it again before `WARM_RELAY_TTL` to keep one ready.
+pub async fn prewarm_relay(relay_server: String, ipv4: bool) -> ResultType<()> {
+    let target = relay_target(relay_server, ipv4);
+    let stream = socket_client::connect_tcp_with_options(
+        target.clone(),
+        None,
+        CONNECT_TIMEOUT,
+        ConnectOptions {
+            buffers: socket_client::get_relay_buffers(),
+            tos: socket_client::get_tos(),
+            ..Default::default()
+        },
+    )
+    .await?;
+    log::debug!("Relay connection to {} is warm", target);
+    *WARM_RELAY.lock().unwrap() = Some(WarmRelay {
+        target,
+        stream,
+        created: Instant::now(),
+    });
+    Ok(())
+}
+
+// `relay-rate-limit` in KB/s, 0 for no limit.
+fn relay_rate_limit() -> u64 {
+    Config::get_option(keys::OPTION_RELAY_RATE_LIMIT)
+        .parse::<u64>()
+        .unwrap_or(0)
+        .saturating_mul(1024)
+}
+
+// Every relay connection has its own bucket, unless `relay-rate-limit-scope` is "global".
+fn relay_bucket() -> Option<SharedBucket> {
+    let rate = relay_rate_limit();
+    if rate == 0 {
+        return None;
+    }
+    if Config::get_option(keys::OPTION_RELAY_RATE_LIMIT_SCOPE) != "global" {
+        return Some(Arc::new(Mutex::new(TokenBucket::new(rate))));
+    }
+    let mut lock = GLOBAL_RELAY_BUCKET.lock().unwrap();
+    match lock.as_ref() {
+        Some(bucket) if bucket.lock().unwrap().rate() == rate => Some(bucket.clone()),
+        _ => {
+            let bucket = Arc::new(Mutex::new(TokenBucket::new(rate)));
+            *lock = Some(bucket.clone());
+            Some(bucket)
+        }
+    }
+}
+
+/// The bytes sent and received over relay connections since start.
+pub fn relay_bytes() -> u64 {
+    RELAY_BYTES.load(Ordering::Relaxed)
+}
+
+/// The relay throughput in bytes per second, sampled at most once a second.
+pub fn relay_throughput() -> u64 {
+    let mut lock = RELAY_THROUGHPUT.lock().unwrap();
+    let elapsed = lock.0.elapsed();
+    if elapsed >= Duration::from_secs(1) {
+        let bytes = relay_bytes();
+        lock.2 = ((bytes - lock.1) as f64 / elapsed.as_secs_f64()) as u64;
+        *lock = (Instant::now(), bytes, lock.2);
+    }
+    lock.2
+}
+
+fn take_warm_relay(target: &str) -> Option<Stream> {
+    let mut lock = WARM_RELAY.lock().unwrap();
+    match lock.take() {
+        Some(w) if w.target == target && w.created.elapsed() < WARM_RELAY_TTL => Some(w.stream),
+        other => {
+            *lock = other;
+            None
+        }
+    }
 }
 
 pub struct Server {
@@ -112,18 +232,41 @@ pub fn new() -> ServerPtr {
     Arc::new(RwLock::new(server))
 }
 
-async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) -> ResultType<()> {
+/// A server without services holding `conns`, for the tests of the connection handling.
+#[cfg(test)]
+pub(crate) fn new_with_connections(conns: Vec<ConnInner>) -> ServerPtr {
+    let server = Server {
+        connections: conns.into_iter().map(|c| (c.id(), c)).collect(),
+        services: HashMap::new(),
+        id_count: 1000,
+    };
+    Arc::new(RwLock::new(server))
+}
+
+/// Waits up to `ms_timeout` for the peer to connect to the address of the punched `socket`,
+/// `None` if it does not.
+pub async fn accept_punched(
+    socket: Stream,
+    ms_timeout: u64,
+) -> ResultType<Option<(Stream, SocketAddr)>> {
     let local_addr = socket.local_addr();
     drop(socket);
     // even we drop socket, below still may fail if not use reuse_addr,
     // there is TIME_WAIT before socket really released, so sometimes we
     // see “Only one usage of each socket address is normally permitted” on windows sometimes,
-    let listener = new_listener(local_addr, true).await?;
+    let listener = new_listener_with_tos(local_addr, socket_client::get_tos()).await?;
     log::info!("Server listening on: {}", &listener.local_addr()?);
-    if let Ok((stream, addr)) = timeout(CONNECT_TIMEOUT, listener.accept()).await? {
-        stream.set_nodelay(true).ok();
-        let stream_addr = stream.local_addr()?;
-        create_tcp_connection(server, Stream::from(stream, stream_addr), addr, secure).await?;
+    let Ok(Ok((stream, addr))) = timeout(ms_timeout, listener.accept()).await else {
+        return Ok(None);
+    };
+    stream.set_nodelay(true).ok();
+    let stream_addr = stream.local_addr()?;
+    Ok(Some((Stream::from(stream, stream_addr), addr)))
+}
+
+async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) -> ResultType<()> {
+    if let Some((stream, addr)) = accept_punched(socket, CONNECT_TIMEOUT).await? {
+        create_tcp_connection(server, stream, addr, secure).await?;
     }
     Ok(())
 }
@@ -241,11 +384,7 @@ async fn create_relay_connection_(
     secure: bool,
     ipv4: bool,
 ) -> ResultType<()> {
-    let mut stream = socket_client::connect_tcp(
-        socket_client::ipv4_to_ipv6(crate::check_port(relay_server, RELAY_PORT), ipv4),
-        CONNECT_TIMEOUT,
-    )
-    .await?;
+    let target = relay_target(relay_server, ipv4);
     let mut msg_out = RendezvousMessage::new();
     let licence_key = crate::get_key(true).await;
     msg_out.set_request_relay(RequestRelay {
@@ -253,7 +392,33 @@ async fn create_relay_connection_(
         uuid,
         ..Default::default()
     });
-    stream.send(&msg_out).await?;
+    let mut warm = take_warm_relay(&target);
+    if let Some(stream) = warm.as_mut() {
+        if let Err(err) = stream.send(&msg_out).await {
+            log::debug!("Warm relay connection to {} is broken: {}", target, err);
+            warm = None;
+        }
+    }
+    let stream = match warm {
+        Some(stream) => stream,
+        None => {
+            let mut stream = socket_client::connect_tcp_with_options(
+                target,
+                None,
+                CONNECT_TIMEOUT,
+                ConnectOptions {
+                    buffers: socket_client::get_relay_buffers(),
+                    tos: socket_client::get_tos(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+            stream.send(&msg_out).await?;
+            stream
+        }
+    };
+    let bucket = relay_bucket();
+    let stream = stream.map_stream(|s| ThrottledStream::new(s, bucket, RELAY_BYTES.clone()));
     create_tcp_connection(server, stream, peer_addr, secure).await?;
     Ok(())
 }
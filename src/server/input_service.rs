@@ -355,15 +355,46 @@ fn run_pos(sp: EmptyExtraFieldService, state: &mut StatePos) -> ResultType<()> {
     Ok(())
 }
 
+// With `wayland-cursor-overlay`, the portal reports the cursor along with the captured frames.
+fn get_cursor() -> ResultType<Option<u64>> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(hcursor) = super::wayland::cursor() {
+            return Ok(Some(hcursor));
+        }
+    }
+    crate::get_cursor()
+}
+
+fn get_cursor_data(hcursor: u64) -> ResultType<CursorData> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(data) = super::wayland::cursor_data(hcursor) {
+            return Ok(data);
+        }
+    }
+    crate::get_cursor_data(hcursor)
+}
+
+fn get_cursor_pos() -> Option<(i32, i32)> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(pos) = super::wayland::cursor_pos() {
+            return Some(pos);
+        }
+    }
+    crate::get_cursor_pos()
+}
+
 fn run_cursor(sp: MouseCursorService, state: &mut StateCursor) -> ResultType<()> {
-    if let Some(hcursor) = crate::get_cursor()? {
+    if let Some(hcursor) = get_cursor()? {
         if hcursor != state.hcursor {
             let msg;
             if let Some(cached) = state.cached_cursor_data.get(&hcursor) {
                 super::log::trace!("Cursor data cached, hcursor: {}", hcursor);
                 msg = cached.clone();
             } else {
-                let mut data = crate::get_cursor_data(hcursor)?;
+                let mut data = get_cursor_data(hcursor)?;
                 data.colors = hbb_common::compress::compress(&data.colors[..]).into();
                 let mut tmp = Message::new();
                 tmp.set_cursor_data(data);
@@ -435,7 +466,7 @@ pub fn try_start_record_cursor_pos() -> Option<thread::JoinHandle<()>> {
             }
 
             let now = time::Instant::now();
-            if let Some((x, y)) = crate::get_cursor_pos() {
+            if let Some((x, y)) = get_cursor_pos() {
                 update_last_cursor_pos(x, y);
             }
             let elapsed = now.elapsed();
@@ -921,6 +952,12 @@ pub fn handle_mouse_(evt: &MouseEvent, conn: i32) {
     }
     match evt_type {
         MOUSE_TYPE_MOVE => {
+            #[cfg(target_os = "linux")]
+            {
+                let (x, y) = super::wayland::map_input_position(evt.x, evt.y);
+                en.mouse_move_to(x, y);
+            }
+            #[cfg(not(target_os = "linux"))]
             en.mouse_move_to(evt.x, evt.y);
             *LATEST_PEER_INPUT_CURSOR.lock().unwrap() = Input {
                 conn,
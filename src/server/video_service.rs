@@ -298,6 +298,7 @@ fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<Ca
         if !is_x11() {
             return super::wayland::get_capturer();
         }
+        super::wayland::claim_capture_backend(super::wayland::CaptureBackend::X11)?;
     }
 
     let mut displays = Display::all()?;
@@ -540,6 +541,11 @@ fn run(vs: VideoService) -> ResultType<()> {
             VRamEncoder::set_fallback_gdi(display_idx, true);
             bail!("SWITCH");
         }
+        #[cfg(target_os = "linux")]
+        if !is_x11() && super::wayland::take_display_switch() {
+            log::info!("switch due to wayland display changed");
+            bail!("SWITCH");
+        }
         check_privacy_mode_changed(&sp, c.privacy_mode_id)?;
         #[cfg(windows)]
         {
@@ -655,6 +661,8 @@ fn run(vs: VideoService) -> ResultType<()> {
             }
         }
 
+        #[cfg(target_os = "linux")]
+        let busy = now.elapsed();
         let mut fetched_conn_ids = HashSet::new();
         let timeout_millis = 3_000u64;
         let wait_begin = Instant::now();
@@ -668,6 +676,10 @@ fn run(vs: VideoService) -> ResultType<()> {
         }
 
         let elapsed = now.elapsed();
+        #[cfg(target_os = "linux")]
+        if !is_x11() {
+            spf = super::wayland::adaptive_spf(spf, busy);
+        }
         // may need to enable frame(timeout)
         log::trace!("{:?} {:?}", time::Instant::now(), elapsed);
         if elapsed < spf {
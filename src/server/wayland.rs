@@ -1,8 +1,16 @@
 use super::*;
-use hbb_common::{allow_err, platform::linux::DISTRO};
-use scrap::{is_cursor_embedded, set_map_err, Capturer, Display, Frame, TraitCapturer};
+use hbb_common::{allow_err, config::keys, platform::linux::DISTRO};
+use scrap::{
+    is_cursor_embedded, set_map_err,
+    wayland::{
+        capturable::{CursorImage, CursorUpdate, DamageRect, Letterbox},
+        pipewire,
+    },
+    Capturer, Display, Frame, TraitCapturer,
+};
 use std::io;
 use std::process::{Command, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::{
     client::{
@@ -14,12 +22,60 @@ use crate::{
 lazy_static::lazy_static! {
     static ref CAP_DISPLAY_INFO: RwLock<u64> = RwLock::new(0);
     static ref LOG_SCRAP_COUNT: Mutex<u32> = Mutex::new(0);
+    // The display chosen by `switch_active_display()`, survives `clear()`.
+    static ref REQUESTED_DISPLAY: RwLock<Option<usize>> = RwLock::new(None);
+    static ref ADAPTIVE_QUALITY: Mutex<AdaptiveQuality> = Default::default();
+    static ref CAPTURE_BACKEND: Mutex<Option<CaptureBackend>> = Default::default();
+    static ref METADATA_CURSOR: Mutex<MetadataCursor> = Default::default();
 }
 
+// Frames counted before the level of `wayland-adaptive` is changed.
+const ADAPTIVE_WINDOW: u32 = 60;
+// The frame rate is halved per level.
+const MAX_ADAPTIVE_LEVEL: u32 = 2;
+const DEFAULT_ADAPTIVE_DROP_PERCENT: u32 = 20;
+const DEFAULT_ADAPTIVE_RECOVER_PERCENT: u32 = 5;
+
+static DISPLAY_SWITCH_PENDING: AtomicBool = AtomicBool::new(false);
+
 pub fn init() {
     set_map_err(map_err_scrap);
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureBackend {
+    X11,
+    Wayland,
+}
+
+/// The backend capturing the screen, `None` after `clear()`.
+pub fn active_capture_backend() -> Option<CaptureBackend> {
+    *CAPTURE_BACKEND.lock().unwrap()
+}
+
+// `is_x11()` may change with the session, the capturer of the old session type has to be
+// cleared before the other backend is initialized, otherwise both capture.
+pub(super) fn claim_capture_backend(backend: CaptureBackend) -> ResultType<()> {
+    claim_backend(&mut CAPTURE_BACKEND.lock().unwrap(), backend)
+}
+
+fn claim_backend(active: &mut Option<CaptureBackend>, backend: CaptureBackend) -> ResultType<()> {
+    match *active {
+        Some(other) if other != backend => {
+            log::error!(
+                "Refuse to initialize {:?} capture, {:?} capture is active, clear it first",
+                backend,
+                other
+            );
+            bail!("{:?} capture is active", other);
+        }
+        _ => {
+            *active = Some(backend);
+            Ok(())
+        }
+    }
+}
+
 fn map_err_scrap(err: String) -> io::Error {
     // to-do: Remove this the following log
     log::error!(
@@ -78,12 +134,50 @@ impl TraitCapturer for CapturerPtr {
     }
 }
 
+type DisplayRect = ((i32, i32), usize, usize);
+
+/// How the frames of the captured display get to the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapturePath {
+    // copied to system memory
+    Cpu,
+    // the DMA-BUF handle is passed on without a copy
+    DmaBuf,
+}
+
+// No encoder takes DMA-BUF input yet, `input_texture()` of the encoders is about D3D11 textures.
+const ENCODER_TAKES_DMABUF: bool = false;
+
+fn select_capture_path(offers_dmabuf: bool, encoder_takes_dmabuf: bool) -> CapturePath {
+    if offers_dmabuf && encoder_takes_dmabuf {
+        CapturePath::DmaBuf
+    } else {
+        CapturePath::Cpu
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureSource {
+    Display,
+    // The PipeWire node id of the shared window.
+    Window(u64),
+}
+
 struct CapDisplayInfo {
-    rects: Vec<((i32, i32), usize, usize)>,
+    rects: Vec<DisplayRect>,
     displays: Vec<DisplayInfo>,
     num: usize,
     primary: usize,
     current: usize,
+    // Set if frames are scaled to `wayland-target-resolution`.
+    letterbox: Option<Letterbox>,
+    source: CaptureSource,
+    // The PipeWire node of each display, `None` if the backend does not tell.
+    node_ids: Vec<Option<u32>>,
+    // Regions of the current display filled with black, see `parse_blank_rects`.
+    blanks: Vec<DamageRect>,
+    dmabuf_offered: bool,
+    capture_path: CapturePath,
     capturer: CapturerPtr,
 }
 
@@ -139,27 +233,42 @@ pub(super) async fn check_init() -> ResultType<()> {
         if *CAP_DISPLAY_INFO.read().unwrap() == 0 {
             let mut lock = CAP_DISPLAY_INFO.write().unwrap();
             if *lock == 0 {
+                claim_capture_backend(CaptureBackend::Wayland)?;
+                let want_window = Config::get_option(keys::OPTION_WAYLAND_CAPTURE_WINDOW) == "Y";
+                pipewire::set_capture_window(want_window);
+                pipewire::set_cursor_overlay(
+                    Config::get_option(keys::OPTION_WAYLAND_CURSOR_OVERLAY) == "Y",
+                );
+                pipewire::set_hdr_passthrough(
+                    Config::get_option(keys::OPTION_WAYLAND_HDR_PASSTHROUGH) == "Y",
+                );
+                pipewire::set_frame_pacing(Config::get_option(keys::OPTION_WAYLAND_PACE) == "Y");
+                pipewire::set_damage_tracking(
+                    Config::get_option(keys::OPTION_WAYLAND_DAMAGE) == "Y",
+                );
+                set_buffer_pool_size();
                 let mut all = Display::all()?;
                 let num = all.len();
                 let primary = super::display_service::get_primary_2(&all);
-                let current = primary;
+                let current = select_current(&mut REQUESTED_DISPLAY.write().unwrap(), num, primary);
+                let windows: Vec<_> = all.iter().map(|d| d.window_token()).collect();
+                let node_ids: Vec<_> = all.iter().map(|d| d.node_id()).collect();
+                let (current, source) = select_capture_source(want_window, &windows, current);
                 super::display_service::check_update_displays(&all);
                 let mut displays = super::display_service::get_sync_displays();
                 for display in displays.iter_mut() {
                     display.cursor_embedded = is_cursor_embedded();
                 }
 
-                let mut rects: Vec<((i32, i32), usize, usize)> = Vec::new();
-                for d in &all {
-                    rects.push((d.origin(), d.width(), d.height()));
-                }
+                let rects = get_display_rects(&all);
 
                 let display = all.remove(current);
                 let (origin, width, height) = (display.origin(), display.width(), display.height());
                 log::debug!(
-                    "#displays={}, current={}, origin: {:?}, width={}, height={}, cpus={}/{}",
+                    "#displays={}, current={}, source={:?}, origin: {:?}, width={}, height={}, cpus={}/{}",
                     num,
                     current,
+                    source,
                     &origin,
                     width,
                     height,
@@ -190,9 +299,38 @@ pub(super) async fn check_init() -> ResultType<()> {
                 miny = 0;
                 maxy = max_height;
 
-                let capturer = Box::into_raw(Box::new(
-                    Capturer::new(display).with_context(|| "Failed to create capturer")?,
-                ));
+                let target = get_target_resolution();
+                let letterbox = target.map(|(w, h)| Letterbox::new(width, height, w, h));
+                if let (Some(lb), Some(d)) = (letterbox, displays.get_mut(current)) {
+                    log::info!("Scale display {} to {:?}", current, lb.dst);
+                    d.width = lb.dst.0 as _;
+                    d.height = lb.dst.1 as _;
+                }
+                let mut capturer =
+                    Capturer::new(display).with_context(|| "Failed to create capturer")?;
+                capturer.set_target_resolution(target);
+                let blanks = parse_blank_rects(
+                    &Config::get_option(keys::OPTION_WAYLAND_BLANK_RECTS),
+                    current,
+                );
+                if !blanks.is_empty() {
+                    log::info!("Blank {} regions of display {}", blanks.len(), current);
+                }
+                capturer.set_blank_rects(blanks.clone());
+                // known once the capturer has created its recorder
+                let dmabuf_offered = node_ids
+                    .get(current)
+                    .cloned()
+                    .flatten()
+                    .map_or(false, |node_id| pipewire::offers_dmabuf(node_id as _));
+                let capture_path = select_capture_path(dmabuf_offered, ENCODER_TAKES_DMABUF);
+                log::info!(
+                    "Display {} offers DMA-BUF: {}, capture path: {:?}",
+                    current,
+                    dmabuf_offered,
+                    capture_path
+                );
+                let capturer = Box::into_raw(Box::new(capturer));
                 let capturer = CapturerPtr(capturer);
                 let cap_display_info = Box::into_raw(Box::new(CapDisplayInfo {
                     rects,
@@ -200,6 +338,12 @@ pub(super) async fn check_init() -> ResultType<()> {
                     num,
                     primary,
                     current,
+                    letterbox,
+                    source,
+                    node_ids,
+                    blanks,
+                    dmabuf_offered,
+                    capture_path,
                     capturer,
                 }));
                 *lock = cap_display_info as _;
@@ -220,6 +364,246 @@ pub(super) async fn check_init() -> ResultType<()> {
     Ok(())
 }
 
+fn parse_resolution(s: &str) -> Option<(usize, usize)> {
+    let (w, h) = s.trim().split_once('x')?;
+    let (w, h) = (w.trim().parse().ok()?, h.trim().parse().ok()?);
+    if w == 0 || h == 0 {
+        return None;
+    }
+    Some((w, h))
+}
+
+// `wayland-blank-rects` of `display`, e.g. "0:100,50,400,300;1:0,0,200,200" for the displays 0
+// and 1, `x,y,width,height` relative to the display. The part off the left or top edge is cut
+// off, the rest is clamped when the frame is blanked. Invalid entries are skipped.
+fn parse_blank_rects(s: &str, display: usize) -> Vec<DamageRect> {
+    s.split(';')
+        .filter_map(|entry| {
+            let (idx, rect) = entry.split_once(':')?;
+            if idx.trim().parse::<usize>().ok()? != display {
+                return None;
+            }
+            let v = rect
+                .split(',')
+                .map(|x| x.trim().parse::<i64>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            let &[x, y, width, height] = v.as_slice() else {
+                return None;
+            };
+            let (x0, y0) = (x.max(0), y.max(0));
+            let (x1, y1) = (x.saturating_add(width), y.saturating_add(height));
+            if x1 <= x0 || y1 <= y0 {
+                return None;
+            }
+            Some(DamageRect {
+                x: x0 as _,
+                y: y0 as _,
+                width: (x1 - x0) as _,
+                height: (y1 - y0) as _,
+            })
+        })
+        .collect()
+}
+
+// e.g. "1920x1080", empty or invalid for the native resolution
+fn get_target_resolution() -> Option<(usize, usize)> {
+    parse_resolution(&Config::get_option(keys::OPTION_WAYLAND_TARGET_RESOLUTION))
+}
+
+// Megabytes, empty or invalid for the default, 0 to allocate every frame buffer.
+fn parse_buffer_pool_size(s: &str) -> usize {
+    s.trim()
+        .parse::<usize>()
+        .map(|mb| mb.saturating_mul(1 << 20))
+        .unwrap_or(scrap::buffer_pool::DEFAULT_POOL_MAX_BYTES)
+}
+
+fn set_buffer_pool_size() {
+    scrap::buffer_pool::set_pool_max_bytes(parse_buffer_pool_size(&Config::get_option(
+        keys::OPTION_FRAME_BUFFER_POOL_SIZE,
+    )));
+}
+
+/// Map a mouse position on the scaled frame back to the real display.
+pub(super) fn map_input_position(x: i32, y: i32) -> (i32, i32) {
+    if is_x11() {
+        return (x, y);
+    }
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr == 0 {
+        return (x, y);
+    }
+    let cap_display_info = unsafe { &*(addr as *const CapDisplayInfo) };
+    match (
+        cap_display_info.letterbox,
+        cap_display_info.rects.get(cap_display_info.current),
+    ) {
+        (Some(lb), Some(((ox, oy), _, _))) => map_to_display(&lb, (*ox, *oy), x, y),
+        _ => (x, y),
+    }
+}
+
+#[inline]
+fn map_to_display(lb: &Letterbox, origin: (i32, i32), x: i32, y: i32) -> (i32, i32) {
+    let (x, y) = lb.to_source(x - origin.0, y - origin.1);
+    (origin.0 + x, origin.1 + y)
+}
+
+// The cursor reported as metadata of the captured stream with `wayland-cursor-overlay`.
+#[derive(Default)]
+struct MetadataCursor {
+    pos: Option<(i32, i32)>,
+    // a hash of the image, 0 before the first one
+    id: u64,
+    data: Option<CursorData>,
+}
+
+impl MetadataCursor {
+    fn update(&mut self, update: CursorUpdate, map: impl Fn(i32, i32) -> (i32, i32)) {
+        self.pos = Some(map(update.x, update.y));
+        if let Some(image) = update.image {
+            self.id = cursor_id(&image);
+            self.data = Some(to_cursor_data(self.id, &image));
+        }
+    }
+}
+
+// The same image gets the same id, so the peers can use the one they cached.
+fn cursor_id(image: &CursorImage) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (image.width, image.height, &image.argb).hash(&mut hasher);
+    hasher.finish().max(1)
+}
+
+// The overlay is placed at the top left corner of the image, so the hotspot is 0.
+fn to_cursor_data(id: u64, image: &CursorImage) -> CursorData {
+    let mut colors = vec![0u8; image.argb.len()];
+    for (argb, rgba) in image.argb.chunks_exact(4).zip(colors.chunks_exact_mut(4)) {
+        let p = u32::from_ne_bytes([argb[0], argb[1], argb[2], argb[3]]);
+        rgba.copy_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8, (p >> 24) as u8]);
+    }
+    CursorData {
+        id,
+        width: image.width as _,
+        height: image.height as _,
+        colors: colors.into(),
+        ..Default::default()
+    }
+}
+
+// Takes the pending cursor updates, `None` if the cursor is drawn into the frames.
+fn metadata_cursor() -> Option<std::sync::MutexGuard<'static, MetadataCursor>> {
+    if is_x11() || !pipewire::is_cursor_metadata() {
+        return None;
+    }
+    let mut cursor = METADATA_CURSOR.lock().unwrap();
+    let updates = pipewire::cursor_updates();
+    if !updates.is_empty() {
+        let addr = *CAP_DISPLAY_INFO.read().unwrap();
+        let (origin, letterbox) = if addr == 0 {
+            ((0, 0), None)
+        } else {
+            let cap_display_info = unsafe { &*(addr as *const CapDisplayInfo) };
+            (
+                cap_display_info
+                    .rects
+                    .get(cap_display_info.current)
+                    .map_or((0, 0), |rect| rect.0),
+                cap_display_info.letterbox,
+            )
+        };
+        for update in updates {
+            // the position is relative to the frame in the native size
+            cursor.update(update, |x, y| {
+                let (x, y) = letterbox.map_or((x, y), |lb| lb.to_target(x, y));
+                (origin.0 + x, origin.1 + y)
+            });
+        }
+    }
+    Some(cursor)
+}
+
+/// The cursor position with `wayland-cursor-overlay`, `None` if it is drawn into the frames.
+pub fn cursor_pos() -> Option<(i32, i32)> {
+    metadata_cursor()?.pos
+}
+
+/// The id of the cursor image with `wayland-cursor-overlay`, `None` if it is drawn into the
+/// frames or no image is reported yet.
+pub fn cursor() -> Option<u64> {
+    Some(metadata_cursor()?.id).filter(|id| *id != 0)
+}
+
+pub fn cursor_data(id: u64) -> Option<CursorData> {
+    metadata_cursor()?.data.clone().filter(|data| data.id == id)
+}
+
+fn select_current(requested: &mut Option<usize>, num: usize, primary: usize) -> usize {
+    match *requested {
+        Some(idx) if idx < num => idx,
+        Some(idx) => {
+            log::warn!(
+                "Requested display {} is gone, {} displays, share the primary one",
+                idx,
+                num
+            );
+            *requested = None;
+            primary
+        }
+        None => primary,
+    }
+}
+
+// `windows` holds the window token of each shared stream, `None` for a monitor.
+fn select_capture_source(
+    want_window: bool,
+    windows: &[Option<u64>],
+    current: usize,
+) -> (usize, CaptureSource) {
+    if !want_window {
+        return (current, CaptureSource::Display);
+    }
+    match windows
+        .iter()
+        .enumerate()
+        .find_map(|(i, w)| w.map(|w| (i, w)))
+    {
+        Some((i, w)) => (i, CaptureSource::Window(w)),
+        None => {
+            log::warn!(
+                "Window capture is not available, capture display {}",
+                current
+            );
+            (current, CaptureSource::Display)
+        }
+    }
+}
+
+fn get_display_rects(all: &[Display]) -> Vec<DisplayRect> {
+    all.iter()
+        .map(|d| (d.origin(), d.width(), d.height()))
+        .collect()
+}
+
+// `Display::all()` only returns the streams cached from the portal, a monitor unplugged after
+// `check_init()` is known by its stream being lost.
+fn has_lost_stream(node_ids: &[Option<u32>], is_lost: impl Fn(u32) -> bool) -> bool {
+    node_ids.iter().flatten().any(|&id| is_lost(id))
+}
+
+pub(super) fn displays_changed() -> bool {
+    if is_x11() {
+        return false;
+    }
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr == 0 {
+        return false;
+    }
+    let node_ids = unsafe { (*(addr as *const CapDisplayInfo)).node_ids.clone() };
+    has_lost_stream(&node_ids, |id| pipewire::is_stream_lost(id as _))
+}
+
 pub(super) async fn get_displays() -> ResultType<Vec<DisplayInfo>> {
     check_init().await?;
     let addr = *CAP_DISPLAY_INFO.read().unwrap();
@@ -234,6 +618,100 @@ pub(super) async fn get_displays() -> ResultType<Vec<DisplayInfo>> {
     }
 }
 
+/// The displays cached by `check_init()`, it does not query the portal again.
+pub fn list_displays() -> ResultType<Vec<DisplayInfo>> {
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr != 0 {
+        let cap_display_info: *const CapDisplayInfo = addr as _;
+        unsafe {
+            let cap_display_info = &*cap_display_info;
+            Ok(cap_display_info.displays.clone())
+        }
+    } else {
+        bail!("Failed to get capturer display info");
+    }
+}
+
+/// The index of the display being captured, `None` before `check_init()`.
+pub fn active_display() -> Option<usize> {
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr == 0 {
+        return None;
+    }
+    Some(unsafe { (*(addr as *const CapDisplayInfo)).current })
+}
+
+/// The captured display, whether the compositor offers DMA-BUF buffers for it, and the path its
+/// frames take. `None` before `check_init()`.
+pub fn capture_path() -> Option<(usize, bool, CapturePath)> {
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr == 0 {
+        return None;
+    }
+    let info = unsafe { &*(addr as *const CapDisplayInfo) };
+    Some((info.current, info.dmabuf_offered, info.capture_path))
+}
+
+/// The regions of the captured display blanked by `wayland-blank-rects`, empty before init.
+pub fn blank_rects() -> Vec<DamageRect> {
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr == 0 {
+        return vec![];
+    }
+    unsafe { (*(addr as *const CapDisplayInfo)).blanks.clone() }
+}
+
+/// The PipeWire node id of each captured display by display index, for external routing tools.
+/// Empty before `check_init()`, displays without a known node are left out.
+pub fn wayland_node_ids() -> Vec<(usize, u32)> {
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr == 0 {
+        return vec![];
+    }
+    index_node_ids(unsafe { &(*(addr as *const CapDisplayInfo)).node_ids })
+}
+
+fn index_node_ids(node_ids: &[Option<u32>]) -> Vec<(usize, u32)> {
+    node_ids
+        .iter()
+        .enumerate()
+        .filter_map(|(i, id)| id.map(|id| (i, id)))
+        .collect()
+}
+
+/// Share another display of the current PipeWire session.
+///
+/// The running video service picks the request up, restarts and captures `display_idx` from
+/// then on. The restore token of the session is reused, so the user is not prompted again.
+/// An invalid index is rejected and the current display stays active.
+pub fn switch_active_display(display_idx: usize) -> ResultType<()> {
+    if is_x11() {
+        bail!("Do not call this function if not wayland");
+    }
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr == 0 {
+        bail!("Failed to get capturer display info");
+    }
+    let (num, current) = unsafe {
+        let cap_display_info = &*(addr as *const CapDisplayInfo);
+        (cap_display_info.num, cap_display_info.current)
+    };
+    if display_idx >= num {
+        bail!("Invalid display index {}, {} displays", display_idx, num);
+    }
+    *REQUESTED_DISPLAY.write().unwrap() = Some(display_idx);
+    if display_idx != current {
+        log::info!("Switch wayland display {} -> {}", current, display_idx);
+        DISPLAY_SWITCH_PENDING.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+#[inline]
+pub(super) fn take_display_switch() -> bool {
+    DISPLAY_SWITCH_PENDING.swap(false, Ordering::SeqCst)
+}
+
 pub(super) fn get_primary() -> ResultType<usize> {
     let addr = *CAP_DISPLAY_INFO.read().unwrap();
     if addr != 0 {
@@ -247,11 +725,11 @@ pub(super) fn get_primary() -> ResultType<usize> {
     }
 }
 
+/// Drops the Wayland capturer and releases the capture backend, also after the session type
+/// changed to X11.
 pub fn clear() {
-    if is_x11() {
-        return;
-    }
     let mut write_lock = CAP_DISPLAY_INFO.write().unwrap();
+    *CAPTURE_BACKEND.lock().unwrap() = None;
     if *write_lock != 0 {
         let cap_display_info: *mut CapDisplayInfo = *write_lock as _;
         unsafe {
@@ -262,32 +740,167 @@ pub fn clear() {
     }
 }
 
+/// Retry the screen selection after a failed `check_init()`, e.g. the picker was dismissed.
+///
+/// A half-open portal session is closed, so the user is asked again. Nothing is done if the
+/// capture is already established, unlike `clear()` it never tears down a working capturer.
+pub fn try_reinit() -> ResultType<()> {
+    if is_x11() {
+        bail!("Do not call this function if not wayland");
+    }
+    {
+        let lock = CAP_DISPLAY_INFO.write().unwrap();
+        if *lock != 0 {
+            return Ok(());
+        }
+        pipewire::close_session();
+    }
+    ensure_inited()
+}
+
 pub(super) fn get_capturer() -> ResultType<super::video_service::CapturerInfo> {
     if is_x11() {
         bail!("Do not call this function if not wayland");
     }
+    if displays_changed() {
+        log::info!("Wayland displays changed, reinitialize the capturer");
+        clear();
+        // the cached streams are stale, ask the portal again
+        pipewire::close_session();
+        ensure_inited()?;
+    }
+    let addr = *CAP_DISPLAY_INFO.read().unwrap();
+    if addr != 0 {
+        let cap_display_info: *const CapDisplayInfo = addr as _;
+        unsafe { get_capturer_info(&*cap_display_info) }
+    } else {
+        bail!("Failed to get capturer display info");
+    }
+}
+
+/// The capturer of a single window shared with `wayland-capture-window`.
+///
+/// Fails if the portal shared a display or another window, `get_capturer()` is used then.
+pub fn get_capturer_for_window(
+    window_token: u64,
+) -> ResultType<super::video_service::CapturerInfo> {
+    if is_x11() {
+        bail!("Do not call this function if not wayland");
+    }
+    ensure_inited()?;
     let addr = *CAP_DISPLAY_INFO.read().unwrap();
     if addr != 0 {
         let cap_display_info: *const CapDisplayInfo = addr as _;
         unsafe {
             let cap_display_info = &*cap_display_info;
-            let rect = cap_display_info.rects[cap_display_info.current];
-            Ok(super::video_service::CapturerInfo {
-                origin: rect.0,
-                width: rect.1,
-                height: rect.2,
-                ndisplay: cap_display_info.num,
-                current: cap_display_info.current,
-                privacy_mode_id: 0,
-                _capturer_privacy_mode_id: 0,
-                capturer: Box::new(cap_display_info.capturer.clone()),
-            })
+            if cap_display_info.source != CaptureSource::Window(window_token) {
+                bail!(
+                    "Window {} is not shared, source: {:?}",
+                    window_token,
+                    cap_display_info.source
+                );
+            }
+            get_capturer_info(cap_display_info)
         }
     } else {
         bail!("Failed to get capturer display info");
     }
 }
 
+fn get_capturer_info(
+    cap_display_info: &CapDisplayInfo,
+) -> ResultType<super::video_service::CapturerInfo> {
+    let Some(rect) = cap_display_info
+        .rects
+        .get(cap_display_info.current)
+        .cloned()
+    else {
+        bail!("Invalid current display index {}", cap_display_info.current);
+    };
+    // The stream size of a window is its own size, not the size of a monitor.
+    let (width, height) = cap_display_info
+        .letterbox
+        .map(|lb| lb.dst)
+        .unwrap_or((rect.1, rect.2));
+    Ok(super::video_service::CapturerInfo {
+        origin: rect.0,
+        width,
+        height,
+        ndisplay: cap_display_info.num,
+        current: cap_display_info.current,
+        privacy_mode_id: 0,
+        _capturer_privacy_mode_id: 0,
+        capturer: Box::new(cap_display_info.capturer.clone()),
+    })
+}
+
+#[derive(Debug, Default)]
+struct AdaptiveQuality {
+    frames: u32,
+    dropped: u32,
+    level: u32,
+}
+
+impl AdaptiveQuality {
+    // The level is raised if at least `drop_percent` of a window of frames are dropped, and
+    // lowered if at most `recover_percent` are.
+    fn on_frame(&mut self, dropped: bool, drop_percent: u32, recover_percent: u32) -> u32 {
+        self.frames += 1;
+        if dropped {
+            self.dropped += 1;
+        }
+        if self.frames >= ADAPTIVE_WINDOW {
+            let percent = self.dropped * 100 / self.frames;
+            if percent >= drop_percent && self.level < MAX_ADAPTIVE_LEVEL {
+                self.level += 1;
+                log::info!("Wayland frames dropped {}%, level {}", percent, self.level);
+            } else if percent <= recover_percent && self.level > 0 {
+                self.level -= 1;
+                log::info!("Wayland frames dropped {}%, level {}", percent, self.level);
+            }
+            self.frames = 0;
+            self.dropped = 0;
+        }
+        self.level
+    }
+}
+
+fn get_adaptive_percent(key: &str, default: u32) -> u32 {
+    match Config::get_option(key).parse::<u32>() {
+        Ok(v) if v <= 100 => v,
+        _ => default,
+    }
+}
+
+/// The interval to wait for the next frame with `wayland-adaptive`.
+///
+/// A frame is dropped if capturing and encoding it, `busy`, takes longer than `spf`. The frame
+/// rate is lowered while frames are dropped and raised again once they fit into `spf`.
+pub(super) fn adaptive_spf(spf: Duration, busy: Duration) -> Duration {
+    let mut adaptive = ADAPTIVE_QUALITY.lock().unwrap();
+    if Config::get_option(keys::OPTION_WAYLAND_ADAPTIVE) != "Y" {
+        *adaptive = Default::default();
+        return spf;
+    }
+    let level = adaptive.on_frame(
+        busy > spf,
+        get_adaptive_percent(
+            keys::OPTION_WAYLAND_ADAPTIVE_DROP_PERCENT,
+            DEFAULT_ADAPTIVE_DROP_PERCENT,
+        ),
+        get_adaptive_percent(
+            keys::OPTION_WAYLAND_ADAPTIVE_RECOVER_PERCENT,
+            DEFAULT_ADAPTIVE_RECOVER_PERCENT,
+        ),
+    );
+    spf * (1 << level)
+}
+
+/// The level `wayland-adaptive` lowered the frame rate to, 0 if it is not lowered.
+pub fn adaptive_level() -> u32 {
+    ADAPTIVE_QUALITY.lock().unwrap().level
+}
+
 pub fn common_get_error() -> String {
     if DISTRO.name.to_uppercase() == "Ubuntu".to_uppercase() {
         if DISTRO.version_id < "21".to_owned() {
@@ -298,3 +911,193 @@ pub fn common_get_error() -> String {
     }
     return "".to_owned();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_capture_path() {
+        assert_eq!(select_capture_path(false, false), CapturePath::Cpu);
+        // falls back to the cpu path if the encoder can't take the buffers
+        assert_eq!(select_capture_path(true, false), CapturePath::Cpu);
+        assert_eq!(select_capture_path(false, true), CapturePath::Cpu);
+        assert_eq!(select_capture_path(true, true), CapturePath::DmaBuf);
+    }
+
+    #[test]
+    fn test_parse_blank_rects() {
+        let rect = |x, y, width, height| DamageRect {
+            x,
+            y,
+            width,
+            height,
+        };
+        let s = "0:100,50,400,300; 1:0,0,200,200;0:-10,-20,30,40;0:1,2,3;0:5,5,0,10;x:1,1,1,1";
+        assert_eq!(
+            parse_blank_rects(s, 0),
+            vec![rect(100, 50, 400, 300), rect(0, 0, 20, 20)]
+        );
+        assert_eq!(parse_blank_rects(s, 1), vec![rect(0, 0, 200, 200)]);
+        assert!(parse_blank_rects(s, 2).is_empty());
+        assert!(parse_blank_rects("", 0).is_empty());
+    }
+
+    #[test]
+    fn test_parse_buffer_pool_size() {
+        assert_eq!(parse_buffer_pool_size("16"), 16 << 20);
+        assert_eq!(parse_buffer_pool_size("0"), 0);
+        let default = scrap::buffer_pool::DEFAULT_POOL_MAX_BYTES;
+        assert_eq!(parse_buffer_pool_size(""), default);
+        assert_eq!(parse_buffer_pool_size("-1"), default);
+    }
+
+    #[test]
+    fn test_metadata_cursor() {
+        let image = CursorImage {
+            width: 1,
+            height: 1,
+            argb: 0x80112233u32.to_ne_bytes().to_vec(),
+        };
+        let mut cursor = MetadataCursor::default();
+        let offset = |x, y| (x + 1920, y);
+        cursor.update(
+            CursorUpdate {
+                x: 10,
+                y: 20,
+                image: Some(image.clone()),
+            },
+            offset,
+        );
+        assert_eq!(cursor.pos, Some((1930, 20)));
+        let id = cursor.id;
+        assert_ne!(id, 0);
+        let data = cursor.data.clone().unwrap();
+        assert_eq!((data.id, data.width, data.height), (id, 1, 1));
+        assert_eq!(&data.colors[..], &[0x11, 0x22, 0x33, 0x80]);
+        // moved, the image is kept
+        cursor.update(
+            CursorUpdate {
+                x: 5,
+                y: 5,
+                image: None,
+            },
+            offset,
+        );
+        assert_eq!(cursor.pos, Some((1925, 5)));
+        assert_eq!(cursor.id, id);
+        // the same image gets the same id
+        assert_eq!(cursor_id(&image), id);
+    }
+
+    #[test]
+    fn test_has_lost_stream() {
+        let node_ids = vec![Some(41), None, Some(42)];
+        assert!(!has_lost_stream(&node_ids, |_| false));
+        // the stream of the second monitor is lost
+        assert!(has_lost_stream(&node_ids, |id| id == 42));
+        assert!(!has_lost_stream(&node_ids, |id| id == 43));
+        assert!(!has_lost_stream(&[], |_| true));
+    }
+
+    #[test]
+    fn test_target_resolution() {
+        assert_eq!(parse_resolution("1920x1080"), Some((1920, 1080)));
+        assert_eq!(parse_resolution(" 1280 x 720 "), Some((1280, 720)));
+        assert_eq!(parse_resolution(""), None);
+        assert_eq!(parse_resolution("0x1080"), None);
+        assert_eq!(parse_resolution("1920"), None);
+        // a 2560x1440 display right of the primary one, scaled to 1280x720
+        let lb = Letterbox::new(2560, 1440, 1280, 720);
+        assert_eq!(map_to_display(&lb, (1920, 0), 1920, 0), (1920, 0));
+        assert_eq!(
+            map_to_display(&lb, (1920, 0), 1920 + 640, 360),
+            (1920 + 1280, 720)
+        );
+        assert_eq!(
+            map_to_display(&lb, (1920, 0), 1920 + 1279, 719),
+            (1920 + 2558, 1438)
+        );
+    }
+
+    #[test]
+    fn test_adaptive_quality() {
+        let mut adaptive = AdaptiveQuality::default();
+        let mut run = |dropped: u32| {
+            let mut level = 0;
+            for i in 0..ADAPTIVE_WINDOW {
+                level = adaptive.on_frame(i < dropped, 20, 5);
+            }
+            level
+        };
+        assert_eq!(run(0), 0);
+        assert_eq!(run(12), 1);
+        assert_eq!(run(30), 2);
+        assert_eq!(run(60), MAX_ADAPTIVE_LEVEL);
+        // between the thresholds
+        assert_eq!(run(6), 2);
+        assert_eq!(run(3), 1);
+        assert_eq!(run(0), 0);
+    }
+
+    #[test]
+    fn test_claim_backend() {
+        let mut active = None;
+        assert!(claim_backend(&mut active, CaptureBackend::X11).is_ok());
+        assert!(claim_backend(&mut active, CaptureBackend::X11).is_ok());
+        // the session changes to Wayland while X11 is capturing
+        assert!(claim_backend(&mut active, CaptureBackend::Wayland).is_err());
+        assert_eq!(active, Some(CaptureBackend::X11));
+        // after clear()
+        active = None;
+        assert!(claim_backend(&mut active, CaptureBackend::Wayland).is_ok());
+        assert_eq!(active, Some(CaptureBackend::Wayland));
+        assert!(claim_backend(&mut active, CaptureBackend::X11).is_err());
+    }
+
+    #[test]
+    fn test_node_ids() {
+        // as collected by check_init from the streams of the portal
+        assert_eq!(
+            index_node_ids(&[Some(42), None, Some(57)]),
+            vec![(0, 42), (2, 57)]
+        );
+        assert!(index_node_ids(&[None]).is_empty());
+        assert!(index_node_ids(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_select_current() {
+        let mut requested = None;
+        assert_eq!(select_current(&mut requested, 2, 0), 0);
+        requested = Some(1);
+        assert_eq!(select_current(&mut requested, 2, 0), 1);
+        assert_eq!(requested, Some(1));
+        // the requested display is unplugged
+        assert_eq!(select_current(&mut requested, 1, 0), 0);
+        assert_eq!(requested, None);
+    }
+
+    #[test]
+    fn test_select_capture_source() {
+        let monitors = [None, None];
+        assert_eq!(
+            select_capture_source(false, &monitors, 1),
+            (1, CaptureSource::Display)
+        );
+        // the portal does not offer window selection
+        assert_eq!(
+            select_capture_source(true, &monitors, 1),
+            (1, CaptureSource::Display)
+        );
+        let window = [None, Some(42)];
+        assert_eq!(
+            select_capture_source(true, &window, 0),
+            (1, CaptureSource::Window(42))
+        );
+        assert_eq!(
+            select_capture_source(false, &window, 0),
+            (0, CaptureSource::Display)
+        );
+    }
+}
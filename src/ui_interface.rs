@@ -444,6 +444,25 @@ pub fn set_socks(proxy: String, username: String, password: String) {
     .ok();
 }
 
+/// Check a proxy before saving it, returns the error or an empty string.
+pub fn check_proxy(proxy: String, username: String, password: String) -> String {
+    if proxy.trim().is_empty() {
+        return "".to_owned();
+    }
+    let conf = config::Socks5Server {
+        proxy,
+        username: username.clone(),
+        password: password.clone(),
+    };
+    match crate::hbbs_http::parse_proxy(&conf) {
+        Ok(info) if username.is_empty() != password.is_empty() && info.basic_auth().is_none() => {
+            "Both the username and the password of the proxy are required".to_owned()
+        }
+        Ok(_) => "".to_owned(),
+        Err(e) => e.to_string(),
+    }
+}
+
 #[inline]
 pub fn get_proxy_status() -> bool {
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -1159,6 +1178,10 @@ async fn check_connect_status_(reconnect: bool, rx: mpsc::UnboundedReceiver<ipc:
                             Ok(Some(ipc::Data::VideoConnCount(Some(n)))) => {
                                 VIDEO_CONN_COUNT.store(n, Ordering::Relaxed);
                             }
+                            Ok(Some(ipc::Data::LanAnnouncements(Some(peers)))) => {
+                                // at most one write per tick however many are announced
+                                crate::lan::store_announced_peers(peers);
+                            }
                             Ok(Some(ipc::Data::OnlineStatus(Some((mut x, _c))))) => {
                                 if x > 0 {
                                     x = 1
@@ -1189,6 +1212,7 @@ async fn check_connect_status_(reconnect: bool, rx: mpsc::UnboundedReceiver<ipc:
                         c.send(&ipc::Data::Config(("id".to_owned(), None))).await.ok();
                         c.send(&ipc::Data::Config(("temporary-password".to_owned(), None))).await.ok();
                         c.send(&ipc::Data::VideoConnCount(None)).await.ok();
+                        c.send(&ipc::Data::LanAnnouncements(None)).await.ok();
                     }
                 }
             }